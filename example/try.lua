@@ -1,8 +1,8 @@
-local _ok_, _err_ = pcall(function()
+local ok__0, err__0 = pcall(function()
 	error("Oh no!")
 end)
-if not _ok_ then
-	local e = _err_
+if not ok__0 then
+	local e = err__0
 	print(e)
 	print("Oh, its fine.")
 end
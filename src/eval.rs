@@ -0,0 +1,601 @@
+use crate::lexer::{Token, TokenKind, Tokens};
+
+/// A value computed by `eval!`. Strings are bytes, as in Lua.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+  Int(i64),
+  Float(f64),
+  Str(Vec<u8>),
+  Bool(bool),
+}
+
+impl Value {
+  fn type_name(&self) -> &'static str {
+    match self {
+      Value::Int(_) | Value::Float(_) => "number",
+      Value::Str(_) => "string",
+      Value::Bool(_) => "boolean",
+    }
+  }
+
+  fn is_truthy(&self) -> bool {
+    *self != Value::Bool(false)
+  }
+
+  fn to_float(&self) -> Option<f64> {
+    match *self {
+      Value::Int(int) => Some(int as f64),
+      Value::Float(float) => Some(float),
+      _ => None,
+    }
+  }
+
+  fn to_int(&self) -> Result<i64, String> {
+    match *self {
+      Value::Int(int) => Ok(int),
+      Value::Float(float)
+        if float.fract() == 0.0
+          && float >= i64::MIN as f64
+          && float < i64::MAX as f64 =>
+      {
+        Ok(float as i64)
+      }
+      Value::Float(_) => {
+        Err("Number has no integer representation".to_string())
+      }
+      _ => Err(format!(
+        "Attempt to perform bitwise operation on a {} value",
+        self.type_name(),
+      )),
+    }
+  }
+
+  /// Converts a string that holds a numeral to that number,
+  /// as Lua does for the operands of arithmetic and bitwise
+  /// operators.
+  fn coerced(self) -> Value {
+    let Value::Str(bytes) = &self else {
+      return self;
+    };
+    let text = String::from_utf8_lossy(bytes);
+    let text = text.trim();
+    let (negative, digits) = match text.strip_prefix('-') {
+      Some(digits) => (true, digits),
+      None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    // Rust reads `inf` and `nan`, which Lua does not.
+    if digits.contains(['n', 'N']) || digits.starts_with(['+', '-']) {
+      return self;
+    }
+    match parse_number(digits) {
+      Some(Value::Int(int)) if negative => Value::Int(int.wrapping_neg()),
+      Some(Value::Float(float)) if negative => Value::Float(-float),
+      Some(number) => number,
+      None => self,
+    }
+  }
+
+  /// Renders a number the way Lua's `tostring` does, with
+  /// floats written by `%.14g`.
+  fn lua_text(&self) -> Option<String> {
+    match *self {
+      Value::Int(int) => Some(int.to_string()),
+      Value::Float(float) => {
+        let text = format_g(float, 14);
+        // Floats that look like integers keep a `.0`.
+        if text.bytes().all(|byte| byte == b'-' || byte.is_ascii_digit()) {
+          Some(text + ".0")
+        } else {
+          Some(text)
+        }
+      }
+      _ => None,
+    }
+  }
+}
+
+/// Formats `float` like C's `%.{precision}g`.
+fn format_g(float: f64, precision: i32) -> String {
+  let sign = if float.is_sign_negative() { "-" } else { "" };
+  if float.is_nan() {
+    return format!("{}nan", sign);
+  } else if float.is_infinite() {
+    return format!("{}inf", sign);
+  } else if float == 0.0 {
+    return format!("{}0", sign);
+  }
+  let trim = |text: String| {
+    if text.contains('.') {
+      text.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+      text
+    }
+  };
+  let scientific = format!("{:.*e}", (precision - 1) as usize, float);
+  let (mantissa, exponent) = scientific.split_once('e').unwrap();
+  let exponent = exponent.parse::<i32>().unwrap();
+  if exponent < -4 || exponent >= precision {
+    format!(
+      "{}e{}{:02}",
+      trim(mantissa.to_string()),
+      if exponent < 0 { '-' } else { '+' },
+      exponent.abs(),
+    )
+  } else {
+    let decimals = (precision - 1 - exponent) as usize;
+    trim(format!("{:.*}", decimals, float))
+  }
+}
+
+/// Binding powers of the binary operators, on the left and
+/// on the right, following Lua's precedence.
+const BINARY_OPERATORS: [(&str, u8, u8); 21] = [
+  ("or", 1, 1),
+  ("and", 2, 2),
+  ("<", 3, 3),
+  (">", 3, 3),
+  ("<=", 3, 3),
+  (">=", 3, 3),
+  ("~=", 3, 3),
+  ("==", 3, 3),
+  ("|", 4, 4),
+  ("~", 5, 5),
+  ("&", 6, 6),
+  ("<<", 7, 7),
+  (">>", 7, 7),
+  ("..", 9, 8),
+  ("+", 10, 10),
+  ("-", 10, 10),
+  ("*", 11, 11),
+  ("/", 11, 11),
+  ("//", 11, 11),
+  ("%", 11, 11),
+  ("^", 14, 13),
+];
+
+const UNARY_PRIORITY: u8 = 12;
+
+fn parse_number(text: &str) -> Option<Value> {
+  let lower = text.to_ascii_lowercase();
+  if let Some(hex) = lower.strip_prefix("0x") {
+    return parse_hex(hex);
+  }
+  if !lower.contains(['.', 'e']) {
+    if let Ok(int) = text.parse() {
+      return Some(Value::Int(int));
+    }
+  }
+  text.parse().ok().map(Value::Float)
+}
+
+/// Reads the digits of a hexadecimal numeral after its `0x`.
+/// Integers wrap around, as in Lua, while a fraction or a
+/// binary exponent after `p` makes it a float.
+fn parse_hex(hex: &str) -> Option<Value> {
+  let (mantissa, exponent) = match hex.split_once('p') {
+    Some((mantissa, exponent)) => (mantissa, Some(exponent.parse().ok()?)),
+    None => (hex, None),
+  };
+  let (int, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+  if exponent.is_none() && !mantissa.contains('.') {
+    return int
+      .chars()
+      .try_fold(0u64, |int, digit| {
+        Some(int.wrapping_mul(16).wrapping_add(digit.to_digit(16)? as u64))
+      })
+      .map(|int| Value::Int(int as i64));
+  }
+  if int.is_empty() && fraction.is_empty() {
+    return None;
+  }
+  let digits = int.chars().chain(fraction.chars()).try_fold(
+    0.0,
+    |float: f64, digit| Some(float * 16.0 + digit.to_digit(16)? as f64),
+  )?;
+  let exponent = exponent.unwrap_or(0) - 4 * fraction.len() as i32;
+  Some(Value::Float(digits * 2f64.powi(exponent)))
+}
+
+/// Decodes the contents of a string literal.
+fn parse_string(text: &str) -> Option<Vec<u8>> {
+  if let Some(rest) = text.strip_prefix('[') {
+    let level = rest.find('[')?;
+    let inner = &text[level + 2..text.len() - level - 2];
+    let inner = inner
+      .strip_prefix("\r\n")
+      .or_else(|| inner.strip_prefix('\n'))
+      .unwrap_or(inner);
+    return Some(inner.as_bytes().to_vec());
+  }
+  let mut bytes = vec![];
+  let mut chars = text[1..text.len() - 1].chars().peekable();
+  while let Some(char) = chars.next() {
+    if char != '\\' {
+      let mut buffer = [0; 4];
+      bytes.extend(char.encode_utf8(&mut buffer).as_bytes());
+      continue;
+    }
+    let escaped = match chars.next()? {
+      'a' => 7,
+      'b' => 8,
+      'f' => 12,
+      'n' | '\n' => b'\n',
+      'r' => b'\r',
+      't' => b'\t',
+      'v' => 11,
+      'x' => {
+        let high = chars.next()?.to_digit(16)?;
+        let low = chars.next()?.to_digit(16)?;
+        (high * 16 + low) as u8
+      }
+      'z' => {
+        while chars.next_if(|char| char.is_whitespace()).is_some() {}
+        continue;
+      }
+      'u' => {
+        chars.next().filter(|&brace| brace == '{')?;
+        let mut code = 0;
+        for digit in chars.by_ref() {
+          if digit == '}' {
+            break;
+          }
+          code = code * 16 + digit.to_digit(16)?;
+        }
+        let mut buffer = [0; 4];
+        bytes.extend(char::from_u32(code)?.encode_utf8(&mut buffer).as_bytes());
+        continue;
+      }
+      digit if digit.is_ascii_digit() => {
+        let mut code = digit.to_digit(10)?;
+        for _ in 0..2 {
+          match chars.next_if(char::is_ascii_digit) {
+            Some(digit) => code = code * 10 + digit.to_digit(10)?,
+            None => break,
+          }
+        }
+        u8::try_from(code).ok()?
+      }
+      char if char.is_ascii() => char as u8,
+      _ => return None,
+    };
+    bytes.push(escaped);
+  }
+  Some(bytes)
+}
+
+/// Writes `bytes` as a double quoted Lua string literal.
+pub fn quote(bytes: &[u8]) -> String {
+  let mut quoted = String::from("\"");
+  let escape = |char: char, quoted: &mut String| match char {
+    '"' => quoted.push_str("\\\""),
+    '\\' => quoted.push_str("\\\\"),
+    '\n' => quoted.push_str("\\n"),
+    '\r' => quoted.push_str("\\r"),
+    '\t' => quoted.push_str("\\t"),
+    char if char.is_control() => {
+      quoted.push_str(&format!("\\{:03}", char as u32))
+    }
+    char => quoted.push(char),
+  };
+  match std::str::from_utf8(bytes) {
+    Ok(text) => text.chars().for_each(|char| escape(char, &mut quoted)),
+    Err(_) => bytes.iter().for_each(|&byte| {
+      if byte.is_ascii() {
+        escape(byte as char, &mut quoted);
+      } else {
+        quoted.push_str(&format!("\\{:03}", byte));
+      }
+    }),
+  }
+  quoted.push('"');
+  quoted
+}
+
+fn floor_div(a: i64, b: i64) -> i64 {
+  let quotient = a.wrapping_div(b);
+  if a.wrapping_rem(b) != 0 && (a < 0) != (b < 0) {
+    quotient - 1
+  } else {
+    quotient
+  }
+}
+
+fn floor_mod(a: i64, b: i64) -> i64 {
+  let remainder = a.wrapping_rem(b);
+  if remainder != 0 && (remainder < 0) != (b < 0) {
+    remainder + b
+  } else {
+    remainder
+  }
+}
+
+/// Shifts left by `n` bits, or right if `n` is negative,
+/// filling with zeros.
+fn shift_left(a: i64, n: i64) -> i64 {
+  if !(-63..=63).contains(&n) {
+    0
+  } else if n >= 0 {
+    ((a as u64) << n) as i64
+  } else {
+    ((a as u64) >> -n) as i64
+  }
+}
+
+fn arithmetic(operator: &str, a: Value, b: Value) -> Result<Value, String> {
+  let (a, b) = (a.coerced(), b.coerced());
+  if let (Value::Int(a), Value::Int(b)) = (&a, &b) {
+    let (a, b) = (*a, *b);
+    let int = match operator {
+      "+" => Some(a.wrapping_add(b)),
+      "-" => Some(a.wrapping_sub(b)),
+      "*" => Some(a.wrapping_mul(b)),
+      "//" | "%" if b == 0 => {
+        return Err(format!("Attempt to perform 'n{}0'", operator))
+      }
+      "//" => Some(floor_div(a, b)),
+      "%" => Some(floor_mod(a, b)),
+      _ => None,
+    };
+    if let Some(int) = int {
+      return Ok(Value::Int(int));
+    }
+  }
+  let (Some(x), Some(y)) = (a.to_float(), b.to_float()) else {
+    let culprit = if a.to_float().is_none() { &a } else { &b };
+    return Err(format!(
+      "Attempt to perform arithmetic on a {} value",
+      culprit.type_name(),
+    ));
+  };
+  Ok(Value::Float(match operator {
+    "+" => x + y,
+    "-" => x - y,
+    "*" => x * y,
+    "/" => x / y,
+    "^" => x.powf(y),
+    "//" => (x / y).floor(),
+    _ => {
+      let remainder = x % y;
+      if remainder != 0.0 && (remainder < 0.0) != (y < 0.0) {
+        remainder + y
+      } else {
+        remainder
+      }
+    }
+  }))
+}
+
+fn compare(operator: &str, a: &Value, b: &Value) -> Result<Value, String> {
+  let ordering = match (a, b) {
+    (Value::Str(a), Value::Str(b)) => a.partial_cmp(b),
+    (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+    _ => match (a.to_float(), b.to_float()) {
+      (Some(x), Some(y)) => x.partial_cmp(&y),
+      _ if matches!(operator, "==" | "~=") => None,
+      _ => {
+        return Err(format!(
+          "Attempt to compare {} with {}",
+          a.type_name(),
+          b.type_name(),
+        ))
+      }
+    },
+  };
+  let equal = ordering == Some(std::cmp::Ordering::Equal)
+    || matches!((a, b), (Value::Bool(a), Value::Bool(b)) if a == b);
+  Ok(Value::Bool(match operator {
+    "==" => equal,
+    "~=" => !equal,
+    "<" => ordering.is_some_and(|ordering| ordering.is_lt()),
+    "<=" => ordering.is_some_and(|ordering| ordering.is_le()),
+    ">" => ordering.is_some_and(|ordering| ordering.is_gt()),
+    _ => ordering.is_some_and(|ordering| ordering.is_ge()),
+  }))
+}
+
+fn binary(operator: &str, a: Value, b: Value) -> Result<Value, String> {
+  match operator {
+    "or" => Ok(if a.is_truthy() { a } else { b }),
+    "and" => Ok(if a.is_truthy() { b } else { a }),
+    "==" | "~=" | "<" | "<=" | ">" | ">=" => compare(operator, &a, &b),
+    ".." => {
+      let mut text = vec![];
+      for value in [&a, &b] {
+        match value {
+          Value::Str(bytes) => text.extend(bytes),
+          value => text.extend(
+            value
+              .lua_text()
+              .ok_or(format!(
+                "Attempt to concatenate a {} value",
+                value.type_name(),
+              ))?
+              .bytes(),
+          ),
+        }
+      }
+      Ok(Value::Str(text))
+    }
+    "&" | "|" | "~" | "<<" | ">>" => {
+      let (a, b) = (a.coerced().to_int()?, b.coerced().to_int()?);
+      Ok(Value::Int(match operator {
+        "&" => a & b,
+        "|" => a | b,
+        "~" => a ^ b,
+        "<<" => shift_left(a, b),
+        _ => shift_left(a, b.wrapping_neg()),
+      }))
+    }
+    _ => arithmetic(operator, a, b),
+  }
+}
+
+struct Evaluator<'a> {
+  tokens: &'a [Token],
+  index: usize,
+  invocation: &'a Token,
+}
+
+impl Evaluator<'_> {
+  fn error<T>(&self, message: String) -> Result<T, String> {
+    let location = match self.tokens.get(self.index) {
+      Some(token) => &token.location,
+      None => &self.invocation.location,
+    };
+    Err(format!(
+      "{}: {} in `{}`{}",
+      location,
+      message,
+      self.invocation.value,
+      location.expansion_trace(),
+    ))
+  }
+
+  fn peek_operator(&self) -> Option<&str> {
+    self
+      .tokens
+      .get(self.index)
+      .filter(|token| {
+        matches!(token.kind, TokenKind::Operator | TokenKind::Keyword)
+      })
+      .map(|token| &*token.value)
+  }
+
+  /// Evaluates operands joined by operators that bind
+  /// tighter than `limit`.
+  fn expression(&mut self, limit: u8) -> Result<Value, String> {
+    let unary = self
+      .peek_operator()
+      .filter(|op| matches!(*op, "-" | "not" | "#" | "~"))
+      .map(String::from);
+    let mut value = match unary {
+      Some(operator) => {
+        self.index += 1;
+        let start = self.index;
+        let operand = self.expression(UNARY_PRIORITY)?;
+        let operand = match operator.as_str() {
+          "-" | "~" => operand.coerced(),
+          _ => operand,
+        };
+        let result = match (operator.as_str(), operand) {
+          ("not", operand) => Ok(Value::Bool(!operand.is_truthy())),
+          ("-", Value::Int(int)) => Ok(Value::Int(int.wrapping_neg())),
+          ("-", Value::Float(float)) => Ok(Value::Float(-float)),
+          ("#", Value::Str(bytes)) => Ok(Value::Int(bytes.len() as i64)),
+          ("~", operand) => operand.to_int().map(|int| Value::Int(!int)),
+          (_, operand) => Err(format!(
+            "Attempt to perform `{}` on a {} value",
+            operator,
+            operand.type_name(),
+          )),
+        };
+        result.or_else(|message| {
+          self.index = start;
+          self.error(message)
+        })?
+      }
+      None => self.operand()?,
+    };
+    while let Some(&(operator, left, right)) =
+      self.peek_operator().and_then(|op| {
+        BINARY_OPERATORS.iter().find(|(operator, _, _)| *operator == op)
+      })
+    {
+      if left <= limit {
+        break;
+      }
+      self.index += 1;
+      let start = self.index;
+      let operand = self.expression(right)?;
+      value = binary(operator, value, operand).or_else(|message| {
+        self.index = start;
+        self.error(message)
+      })?;
+    }
+    Ok(value)
+  }
+
+  fn operand(&mut self) -> Result<Value, String> {
+    let Some(token) = self.tokens.get(self.index) else {
+      return self.error("Expected a value, found the end".to_string());
+    };
+    let value = match token.kind {
+      TokenKind::Number => parse_number(&token.value),
+      TokenKind::String => parse_string(&token.value).map(Value::Str),
+      TokenKind::Boolean => Some(Value::Bool(&*token.value == "true")),
+      TokenKind::Brace if &*token.value == "(" => {
+        self.index += 1;
+        let value = self.expression(0)?;
+        match self.tokens.get(self.index) {
+          Some(token) if &*token.value == ")" => {}
+          _ => return self.error("Expected `)`".to_string()),
+        }
+        Some(value)
+      }
+      _ => None,
+    };
+    let Some(value) = value else {
+      return self.error(format!("Cannot evaluate `{}`", token.value));
+    };
+    self.index += 1;
+    Ok(value)
+  }
+}
+
+fn evaluate<'a>(
+  tokens: &'a [Token],
+  invocation: &'a Token,
+) -> Result<(Value, Evaluator<'a>), String> {
+  let mut evaluator = Evaluator {
+    tokens,
+    index: 0,
+    invocation,
+  };
+  let value = evaluator.expression(0)?;
+  if let Some(token) = tokens.get(evaluator.index) {
+    return evaluator
+      .error(format!("Expected an operator, found `{}`", token.value));
+  }
+  Ok((value, evaluator))
+}
+
+/// Evaluates the expanded condition of `#if`, which holds
+/// unless it is `false`.
+pub fn truth(tokens: &[Token], directive: &Token) -> Result<bool, String> {
+  Ok(evaluate(tokens, directive)?.0.is_truthy())
+}
+
+/// Folds the expanded arguments of an `eval!` invocation into
+/// a single number, string or boolean. Infinities and NaN,
+/// which have no literal, are written as `(1/0)`, `(-1/0)`
+/// and `(0/0)`.
+pub fn eval(tokens: &[Token], invocation: &Token) -> Result<Tokens, String> {
+  let (value, _) = evaluate(tokens, invocation)?;
+  let token = |kind, value: &str| Token {
+    kind,
+    value: value.into(),
+    location: invocation.location.clone(),
+  };
+  let literal = match value {
+    Value::Str(bytes) => token(TokenKind::String, &quote(&bytes)),
+    Value::Bool(bool) => token(TokenKind::Boolean, &bool.to_string()),
+    Value::Float(float) if !float.is_finite() => {
+      let mut division = vec![token(TokenKind::Brace, "(")];
+      if float < 0.0 {
+        division.push(token(TokenKind::Operator, "-"));
+      }
+      let numerator = if float.is_nan() { "0" } else { "1" };
+      division.extend([
+        token(TokenKind::Number, numerator),
+        token(TokenKind::Operator, "/"),
+        token(TokenKind::Number, "0"),
+        token(TokenKind::Brace, ")"),
+      ]);
+      return Ok(division);
+    }
+    Value::Int(int) => token(TokenKind::Number, &int.to_string()),
+    // Debug formatting writes floats the way Lua reads them back.
+    Value::Float(float) => token(TokenKind::Number, &format!("{:?}", float)),
+  };
+  Ok(vec![literal])
+}
@@ -0,0 +1,97 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A source file, identified by its index in the file
+/// table. The default id names no file and is used for
+/// tokens the preprocessor makes up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct FileId(u32);
+
+struct SourceFile {
+  name: Rc<str>,
+  source: Option<Rc<str>>,
+}
+
+struct FileTable {
+  files: Vec<SourceFile>,
+  ids: HashMap<Rc<str>, FileId>,
+}
+
+thread_local! {
+  static FILES: RefCell<FileTable> = RefCell::new(FileTable {
+    files: vec![SourceFile {
+      name: "".into(),
+      source: None,
+    }],
+    ids: HashMap::new(),
+  });
+}
+
+/// Returns the id of the file called `name`, adding it to
+/// the table on first use.
+pub fn intern(name: &str) -> FileId {
+  FILES.with_borrow_mut(|table| {
+    if let Some(&id) = table.ids.get(name) {
+      return id;
+    }
+    let id = FileId(table.files.len() as u32);
+    let name: Rc<str> = name.into();
+    table.files.push(SourceFile {
+      name: name.clone(),
+      source: None,
+    });
+    table.ids.insert(name, id);
+    id
+  })
+}
+
+/// Interns `name` and keeps `source` as its contents, to be
+/// quoted in diagnostics.
+pub fn add_source(name: &str, source: &str) -> FileId {
+  let id = intern(name);
+  FILES.with_borrow_mut(|table| {
+    table.files[id.0 as usize].source = Some(source.into());
+  });
+  id
+}
+
+impl FileId {
+  pub fn name(self) -> Rc<str> {
+    FILES.with_borrow(|table| table.files[self.0 as usize].name.clone())
+  }
+
+  pub fn source(self) -> Option<Rc<str>> {
+    FILES.with_borrow(|table| {
+      table.files[self.0 as usize].source.clone()
+    })
+  }
+
+  /// Returns the text of the given line, starting at 1.
+  pub fn line(self, line: i32) -> Option<String> {
+    let source = self.source()?;
+    let index = usize::try_from(line).ok()?.checked_sub(1)?;
+    let text = split_lines(&source).nth(index).map(String::from);
+    text
+  }
+}
+
+/// Splits `source` into lines the way the lexer counts them,
+/// where `\n`, `\r`, `\r\n` and `\n\r` each end a line.
+fn split_lines(source: &str) -> impl Iterator<Item = &str> {
+  let mut rest = Some(source);
+  std::iter::from_fn(move || {
+    let text = rest?;
+    let Some(end) = text.find(['\n', '\r']) else {
+      rest = None;
+      return Some(text);
+    };
+    let bytes = text.as_bytes();
+    let next = match bytes.get(end + 1) {
+      Some(&byte @ (b'\n' | b'\r')) if byte != bytes[end] => end + 2,
+      _ => end + 1,
+    };
+    rest = Some(&text[next..]);
+    Some(&text[..end])
+  })
+}
@@ -0,0 +1,195 @@
+use crate::lexer::{Token, TokenKind, Tokens};
+
+const STATEMENT_KEYWORDS: [&str; 8] = [
+  "local", "return", "if", "for", "while", "repeat", "function",
+  "goto",
+];
+
+
+/// Whether `token` can be the last token of an expression
+/// or of a statement, so that a name following it must
+/// belong to the next statement.
+pub fn ends_expression(token: &Token) -> bool {
+  match token.kind {
+    TokenKind::Number
+    | TokenKind::String
+    | TokenKind::Boolean
+    | TokenKind::Nil => true,
+    TokenKind::Name => true,
+    TokenKind::Keyword => {
+      token.is_keyword("end") || token.is_keyword("break")
+    }
+    _ => matches!(&*token.value, ")" | "]" | "}" | "..."),
+  }
+}
+
+/// Whether `tokens[index]` is the `::` that closes a label.
+fn closes_label(tokens: &[Token], index: usize) -> bool {
+  &*tokens[index].value == "::"
+    && index >= 2
+    && &*tokens[index - 2].value == "::"
+}
+
+/// Whether `tokens[index]`, written at the statement level
+/// of a block, begins a new statement. Only the `::` that
+/// opens a label does, and the one closing it ends one.
+pub fn starts_statement(tokens: &[Token], index: usize) -> bool {
+  let token = &tokens[index];
+  if !ends_expression(&tokens[index - 1])
+    && !closes_label(tokens, index - 1)
+  {
+    return false;
+  }
+  match token.kind {
+    TokenKind::Name => return true,
+    TokenKind::Keyword => {
+      return STATEMENT_KEYWORDS.contains(&&*token.value)
+        || token.is_keyword("break")
+        || token.is_keyword("do");
+    }
+    _ => {}
+  }
+  &*token.value == "::" && !closes_label(tokens, index)
+}
+
+fn needs_space(prev: &Token, token: &Token) -> bool {
+  let value = &*token.value;
+  if matches!(value, ")" | "]" | "," | ";" | "." | ":") {
+    return false;
+  }
+  if matches!(&*prev.value, "(" | "[" | "." | ":") {
+    return false;
+  }
+  if &*prev.value == "{" && value == "}" {
+    return false;
+  }
+  if matches!(value, "(" | "[")
+    && (ends_expression(prev) && prev.kind != TokenKind::Keyword
+      || prev.is_keyword("function"))
+  {
+    return false;
+  }
+  true
+}
+
+fn is_unary(prev: Option<&Token>, token: &Token) -> bool {
+  matches!(&*token.value, "-" | "#" | "~")
+    && !prev.is_some_and(ends_expression)
+}
+
+struct Formatter {
+  output: String,
+  indent_width: usize,
+  level: usize,
+  depth: usize,
+  blocks: Vec<usize>,
+  function_depths: Vec<usize>,
+  in_loop_header: bool,
+  at_line_start: bool,
+  after_unary: bool,
+}
+
+impl Formatter {
+  fn newline(&mut self) {
+    if !self.output.is_empty() {
+      self.at_line_start = true;
+    }
+  }
+
+  fn open_block(&mut self) {
+    self.blocks.push(self.depth);
+    self.level += 1;
+    self.newline();
+  }
+
+  fn close_block(&mut self) {
+    self.blocks.pop();
+    self.level = self.level.saturating_sub(1);
+    self.newline();
+  }
+
+  fn at_statement_level(&self) -> bool {
+    self.blocks.last().copied().unwrap_or(0) == self.depth
+  }
+
+  fn write(&mut self, prev: Option<&Token>, token: &Token) {
+    if self.at_line_start {
+      self.output.push('\n');
+      let width = self.level * self.indent_width;
+      self.output.push_str(&" ".repeat(width));
+      self.at_line_start = false;
+    } else if let Some(prev) = prev {
+      // A unary operator binds to its operand, except where
+      // `- -x` would otherwise turn into a comment.
+      let glued = self.after_unary
+        && !(&*prev.value == "-" && token.value.starts_with('-'));
+      if !glued && needs_space(prev, token) {
+        self.output.push(' ');
+      }
+    }
+    self.output.push_str(&token.value);
+  }
+}
+
+/// Pretty prints a token stream as Lua source, breaking
+/// lines at statement boundaries and indenting the bodies
+/// of blocks by `indent_width` spaces per level.
+pub fn format_tokens(tokens: &Tokens, indent_width: usize) -> String {
+  let mut fmt = Formatter {
+    output: String::new(),
+    indent_width,
+    level: 0,
+    depth: 0,
+    blocks: vec![],
+    function_depths: vec![],
+    in_loop_header: false,
+    at_line_start: false,
+    after_unary: false,
+  };
+  let mut prev: Option<&Token> = None;
+  for (index, token) in tokens.iter().enumerate() {
+    let value = &*token.value;
+    if token.kind == TokenKind::Keyword
+      && matches!(value, "end" | "until" | "else" | "elseif")
+    {
+      fmt.close_block();
+    } else if fmt.at_statement_level()
+      && index > 0
+      && starts_statement(tokens, index)
+      && !(token.is_keyword("do") && fmt.in_loop_header)
+    {
+      fmt.newline();
+    }
+    fmt.write(prev, token);
+    match value {
+      "(" | "[" | "{" => fmt.depth += 1,
+      ")" | "]" | "}" => {
+        fmt.depth = fmt.depth.saturating_sub(1);
+        if value == ")"
+          && fmt.function_depths.last() == Some(&fmt.depth)
+        {
+          fmt.function_depths.pop();
+          fmt.open_block();
+        }
+      }
+      ";" if fmt.at_statement_level() => fmt.newline(),
+      _ => {}
+    }
+    if token.kind == TokenKind::Keyword {
+      match value {
+        "then" | "else" | "repeat" => fmt.open_block(),
+        "do" => {
+          fmt.in_loop_header = false;
+          fmt.open_block();
+        }
+        "for" | "while" => fmt.in_loop_header = true,
+        "function" => fmt.function_depths.push(fmt.depth),
+        _ => {}
+      }
+    }
+    fmt.after_unary = is_unary(prev, token);
+    prev = Some(token);
+  }
+  fmt.output.push('\n');
+  fmt.output
+}
@@ -0,0 +1,194 @@
+use crate::format::starts_statement;
+use crate::lexer::{TokenKind, Tokens};
+use crate::minify::{is_field, name_list};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static EXPANSION_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A local declared by a macro body, together with the
+/// range of body tokens it is visible in.
+struct Declaration {
+  name: String,
+  start: usize,
+  block: usize,
+}
+
+/// Marks the tokens whose innermost bracket is a `{`.
+fn table_positions(tokens: &Tokens) -> Vec<bool> {
+  let mut brackets = vec![];
+  tokens
+    .iter()
+    .map(|token| {
+      let in_table = brackets.last() == Some(&"{");
+      match &*token.value {
+        "(" | "[" | "{" => brackets.push(&*token.value),
+        ")" | "]" | "}" => {
+          brackets.pop();
+        }
+        _ => {}
+      }
+      in_table
+    })
+    .collect()
+}
+
+/// Finds the end of the expression following `until`.
+fn until_end(tokens: &Tokens, index: usize) -> usize {
+  let mut depth = 0i32;
+  for i in index + 1..tokens.len() {
+    match &*tokens[i].value {
+      "(" | "[" | "{" => depth += 1,
+      ")" | "]" | "}" => depth -= 1,
+      _ => {}
+    }
+    if depth == 0 && starts_statement(tokens, i) {
+      return i - 1;
+    }
+  }
+  tokens.len() - 1
+}
+
+/// Finds the locals declared by `tokens` whose scope also
+/// ends within `tokens`, returning each declared name with
+/// the range of token indices it is visible in.
+fn scoped_locals(tokens: &Tokens) -> Vec<(String, usize, usize)> {
+  let mut scoped = vec![];
+  let mut blocks: Vec<usize> = vec![];
+  let mut declarations: Vec<Declaration> = vec![];
+  let mut loop_vars: Vec<usize> = vec![];
+  let mut next_block = 0;
+  let declare = |declarations: &mut Vec<Declaration>,
+                 indices: Vec<usize>,
+                 block: usize| {
+    for index in indices {
+      declarations.push(Declaration {
+        name: tokens[index].value.to_string(),
+        start: index,
+        block,
+      });
+    }
+  };
+  for (index, token) in tokens.iter().enumerate() {
+    if token.kind != TokenKind::Keyword {
+      continue;
+    }
+    match &*token.value {
+      "function" => {
+        next_block += 1;
+        blocks.push(next_block);
+        let mut at = index + 1;
+        while tokens.get(at).is_some_and(|t| &*t.value != "(") {
+          at += 1;
+        }
+        let params = name_list(tokens, at + 1).0;
+        declare(&mut declarations, params, next_block);
+      }
+      "for" => loop_vars = name_list(tokens, index + 1).0,
+      "do" | "then" | "repeat" => {
+        next_block += 1;
+        blocks.push(next_block);
+        let vars = std::mem::take(&mut loop_vars);
+        declare(&mut declarations, vars, next_block);
+      }
+      "local" => {
+        let at = if tokens
+          .get(index + 1)
+          .is_some_and(|t| t.is_keyword("function"))
+        {
+          index + 2
+        } else {
+          index + 1
+        };
+        if let Some(&block) = blocks.last() {
+          declare(&mut declarations, name_list(tokens, at).0, block);
+        }
+      }
+      "end" | "until" | "else" | "elseif" => {
+        let Some(block) = blocks.pop() else {
+          continue;
+        };
+        let end = if &*token.value == "until" {
+          until_end(tokens, index)
+        } else {
+          index
+        };
+        declarations.retain(|decl| {
+          if decl.block != block {
+            return true;
+          }
+          scoped.push((decl.name.clone(), decl.start, end));
+          false
+        });
+        // `else` opens the next branch, while an `elseif`
+        // branch is opened by its `then`.
+        if &*token.value == "else" {
+          next_block += 1;
+          blocks.push(next_block);
+        }
+      }
+      _ => {}
+    }
+  }
+  scoped
+}
+
+/// Removes `#gensym` directives from a macro body, returning
+/// the names they list.
+fn take_gensyms(tokens: Tokens) -> (Tokens, Vec<String>) {
+  let mut body = vec![];
+  let mut names = vec![];
+  let mut iter = tokens.into_iter().peekable();
+  while let Some(token) = iter.next() {
+    if token.kind != TokenKind::Gensym {
+      body.push(token);
+      continue;
+    }
+    while let Some(name) =
+      iter.next_if(|name| name.kind == TokenKind::Name)
+    {
+      names.push(name.value.to_string());
+      if iter.next_if(|comma| &*comma.value == ",").is_none() {
+        break;
+      }
+    }
+  }
+  (body, names)
+}
+
+/// Gives the identifiers a macro body introduces a name that
+/// is unique to this expansion, so that they cannot collide
+/// with the caller's names or with other expansions. These
+/// are the locals whose scope ends within the body and the
+/// names listed by `#gensym`. Parameters are left alone,
+/// since they are replaced by the caller's tokens.
+pub fn rename_introduced(
+  tokens: Tokens,
+  params: &[String],
+) -> Tokens {
+  let (mut tokens, gensyms) = take_gensyms(tokens);
+  if tokens.is_empty() {
+    return tokens;
+  }
+  let id = EXPANSION_COUNT.fetch_add(1, Ordering::Relaxed);
+  let tables = table_positions(&tokens);
+  let mut renames = vec![];
+  for name in gensyms {
+    renames.push((name, 0, tokens.len() - 1));
+  }
+  renames.extend(scoped_locals(&tokens));
+  for (name, start, end) in renames {
+    if params.contains(&name) {
+      continue;
+    }
+    for index in start..=end {
+      let token = &tokens[index];
+      if token.kind == TokenKind::Name
+        && *token.value == name
+        && !is_field(&tokens, index, tables[index])
+      {
+        tokens[index].value = format!("{}__{}", name, id).into();
+      }
+    }
+  }
+  tokens
+}
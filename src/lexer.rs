@@ -1,4 +1,7 @@
+use crate::files::{self, FileId};
 use regex::Regex;
+use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenKind {
@@ -7,6 +10,8 @@ pub enum TokenKind {
   Boolean,
   Nil,
   Name,
+  Keyword,
+  Operator,
   Special,
   Delimiter,
   Brace,
@@ -25,140 +30,630 @@ pub enum TokenKind {
   StringifyVararg,
   Line,
   Bang,
+  Gensym,
+  Rules,
+  For,
+  EndFor,
+  If,
+  Lua,
+  EndLua,
+  PushMacro,
+  PopMacro,
+  Scope,
+  EndScope,
+  Namespace,
+  EndNamespace,
+  Using,
+  Private,
+  Export,
 }
 
-#[derive(Debug, Clone)]
+/// A macro invocation that produced a token.
+#[derive(Debug)]
+pub struct Expansion {
+  pub name: String,
+  pub location: Location,
+}
+
+/// A range of byte offsets into a source file.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+  pub start: u32,
+  pub end: u32,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Location {
-  pub file: String,
+  pub file: FileId,
   pub line: i32,
+  /// Column in bytes, starting at 1.
   pub column: i32,
+  /// Column in UTF-16 code units, starting at 1, as used by
+  /// the language server protocol.
+  pub utf16_column: i32,
+  pub span: Span,
+  pub expansion: Option<Rc<Expansion>>,
+}
+
+impl Location {
+  /// Lists the chain of macro invocations that expanded
+  /// to this location, innermost first.
+  pub fn expansion_trace(&self) -> String {
+    let mut trace = String::new();
+    let mut expansion = self.expansion.as_ref();
+    while let Some(exp) = expansion {
+      trace += &format!(
+        "\n  in expansion of `{}` at {}",
+        exp.name, exp.location,
+      );
+      expansion = exp.location.expansion.as_ref();
+    }
+    trace
+  }
+
+  /// Quotes the source line of this location, underlining
+  /// its span, or returns an empty string if the source is
+  /// not known.
+  pub fn excerpt(&self) -> String {
+    let Some(text) = self.file.line(self.line) else {
+      return String::new();
+    };
+    let start = (self.column - 1) as usize;
+    let Some(before) = text.get(..start) else {
+      return String::new();
+    };
+    let len = (self.span.end - self.span.start) as usize;
+    let end = text.len().min(start + len);
+    let width = text.get(start..end).map_or(0, |s| s.chars().count());
+    let indent = before
+      .chars()
+      .map(|char| if char == '\t' { '\t' } else { ' ' })
+      .collect::<String>();
+    let gutter = " ".repeat(self.line.to_string().len());
+    format!(
+      "\n{} | {}\n{} | {}{}",
+      self.line,
+      text,
+      gutter,
+      indent,
+      "^".repeat(width.max(1)),
+    )
+  }
+}
+
+impl fmt::Display for Location {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}:{}", self.file.name(), self.line, self.column)
+  }
 }
 
 #[derive(Debug, Clone)]
 pub struct Token {
   pub kind: TokenKind,
-  pub value: String,
+  /// The source text of the token, shared between clones.
+  pub value: Rc<str>,
   pub location: Location,
 }
 
-pub type Patterns = Vec<(Regex, TokenKind)>;
+impl Token {
+  pub fn is_keyword(&self, keyword: &str) -> bool {
+    self.kind == TokenKind::Keyword && &*self.value == keyword
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexErrorKind {
+  UnterminatedString,
+  UnterminatedComment,
+  UnknownCharacter(char),
+  MalformedNumber(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct LexError {
+  pub kind: LexErrorKind,
+  pub location: Location,
+}
+
+impl fmt::Display for LexError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self.kind {
+      LexErrorKind::UnterminatedString => {
+        write!(f, "{}: Unterminated string literal", self.location)?
+      }
+      LexErrorKind::UnterminatedComment => {
+        write!(f, "{}: Unterminated long comment", self.location)?
+      }
+      LexErrorKind::UnknownCharacter(char) => {
+        write!(f, "{}: Unknown character {:?}", self.location, char)?
+      }
+      LexErrorKind::MalformedNumber(number) => {
+        write!(f, "{}: Malformed number `{}`", self.location, number)?
+      }
+    }
+    write!(f, "{}", self.location.excerpt())
+  }
+}
+
+/// Joins the errors `lex` returns into one message, with an
+/// error per line.
+pub fn join_errors(errors: Vec<LexError>) -> String {
+  errors
+    .iter()
+    .map(|error| error.to_string())
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
 pub type Tokens = Vec<Token>;
 
-fn new_pattern(pattern: &str) -> Regex {
-  Regex::new(&("^".to_string() + pattern)).unwrap()
-}
-
-fn get_lex_patterns() -> Patterns {
-  vec![
-    (new_pattern(r"-?\d+(\.\d+)?"), TokenKind::Number),
-    (new_pattern(r#""([^"\\]|\\.)*""#), TokenKind::String),
-    (new_pattern(r"(true|false)"), TokenKind::Boolean),
-    (new_pattern(r"([a-zA-Z_]\w*!)"), TokenKind::Macro),
-    (new_pattern(r"(#include)"), TokenKind::Include),
-    (new_pattern(r"(#line)"), TokenKind::Line),
-    (new_pattern(r"(#ifndef)"), TokenKind::Ifndef),
-    (new_pattern(r"(#ifdef)"), TokenKind::Ifdef),
-    (new_pattern(r"(#endif)"), TokenKind::Endif),
-    (new_pattern(r"(#else)"), TokenKind::Else),
-    (new_pattern(r"(#define)"), TokenKind::Define),
-    (new_pattern(r"(#end)"), TokenKind::EndDefine),
-    (new_pattern(r"(#undef)"), TokenKind::Undef),
-    (new_pattern(r"(nil)"), TokenKind::Nil),
-    (new_pattern(r"(#[a-zA-Z_]\w*#)"), TokenKind::Stringify),
-    (new_pattern(r"##!"), TokenKind::Bang),
-    (new_pattern(r"##"), TokenKind::Paste),
-    (new_pattern(r"(,|;|:=|->|=>|\bis\b)"), TokenKind::Delimiter),
-    (new_pattern(r"([a-zA-Z_]\w*)"), TokenKind::Name),
-    (new_pattern(r"#\.\.\.#"), TokenKind::StringifyVararg),
-    (new_pattern(r"#\.\.\."), TokenKind::Vararg),
-    (
-      new_pattern(r"([+\-*/!@#$%&|:<>=?~^.]+)"),
-      TokenKind::Special,
-    ),
-    (new_pattern(r"[()\[\]{}]"), TokenKind::Brace),
-  ]
+pub const KEYWORDS: [&str; 19] = [
+  "and", "break", "do", "else", "elseif", "end", "for", "function",
+  "goto", "if", "in", "local", "not", "or", "repeat", "return",
+  "then", "until", "while",
+];
+
+const DIRECTIVES: [(&str, TokenKind); 25] = [
+  ("include", TokenKind::Include),
+  ("line", TokenKind::Line),
+  ("ifndef", TokenKind::Ifndef),
+  ("ifdef", TokenKind::Ifdef),
+  ("if", TokenKind::If),
+  ("endif", TokenKind::Endif),
+  ("else", TokenKind::Else),
+  ("define", TokenKind::Define),
+  ("end", TokenKind::EndDefine),
+  ("undef", TokenKind::Undef),
+  ("gensym", TokenKind::Gensym),
+  ("rules", TokenKind::Rules),
+  ("for", TokenKind::For),
+  ("endfor", TokenKind::EndFor),
+  ("lua", TokenKind::Lua),
+  ("endlua", TokenKind::EndLua),
+  ("push_macro", TokenKind::PushMacro),
+  ("pop_macro", TokenKind::PopMacro),
+  ("scope", TokenKind::Scope),
+  ("endscope", TokenKind::EndScope),
+  ("namespace", TokenKind::Namespace),
+  ("endnamespace", TokenKind::EndNamespace),
+  ("using", TokenKind::Using),
+  ("private", TokenKind::Private),
+  ("export", TokenKind::Export),
+];
+
+/// Directives that only start a statement. After a token
+/// that expects an operand, they are Lua's length operator
+/// applied to a name instead.
+const STATEMENT_DIRECTIVES: [TokenKind; 13] = [
+  TokenKind::Rules,
+  TokenKind::Lua,
+  TokenKind::EndLua,
+  TokenKind::PushMacro,
+  TokenKind::PopMacro,
+  TokenKind::Scope,
+  TokenKind::EndScope,
+  TokenKind::Namespace,
+  TokenKind::EndNamespace,
+  TokenKind::Using,
+  TokenKind::Private,
+  TokenKind::Export,
+  TokenKind::Gensym,
+];
+
+/// Symbols of more than one character, longest first.
+const SYMBOLS: [(&str, TokenKind); 17] = [
+  ("#...#", TokenKind::StringifyVararg),
+  ("#...", TokenKind::Vararg),
+  ("##!", TokenKind::Bang),
+  ("...", TokenKind::Operator),
+  ("##", TokenKind::Paste),
+  (":=", TokenKind::Delimiter),
+  ("->", TokenKind::Delimiter),
+  ("=>", TokenKind::Delimiter),
+  ("..", TokenKind::Operator),
+  ("==", TokenKind::Operator),
+  ("~=", TokenKind::Operator),
+  ("<=", TokenKind::Operator),
+  (">=", TokenKind::Operator),
+  ("<<", TokenKind::Operator),
+  (">>", TokenKind::Operator),
+  ("//", TokenKind::Operator),
+  ("::", TokenKind::Operator),
+];
+
+fn symbol_kind(char: char) -> Option<TokenKind> {
+  match char {
+    ',' | ';' => Some(TokenKind::Delimiter),
+    '+' | '-' | '*' | '/' | '%' | '^' | '#' | '&' | '~' | '|' | '<'
+    | '>' | '=' | ':' | '.' => Some(TokenKind::Operator),
+    '!' | '@' | '$' | '?' => Some(TokenKind::Special),
+    '(' | ')' | '[' | ']' | '{' | '}' => Some(TokenKind::Brace),
+    _ => None,
+  }
 }
 
 fn update_location(
   input: &str,
   location: &mut Location,
 ) {
-  for char in input.chars() {
-    if char == '\n' {
+  let mut chars = input.chars().peekable();
+  while let Some(char) = chars.next() {
+    if char == '\n' || char == '\r' {
+      // `\r\n` and `\n\r` count as a single line break.
+      chars.next_if(|next| {
+        (*next == '\n' || *next == '\r') && *next != char
+      });
       location.line += 1;
       location.column = 1;
+      location.utf16_column = 1;
     } else {
-      location.column += 1;
+      location.column += char.len_utf8() as i32;
+      location.utf16_column += char.len_utf16() as i32;
     }
   }
 }
 
-fn apply_pattern(
-  pattern: Regex,
-  kind: TokenKind,
-  input: &str,
-  location: &mut Location,
-) -> Option<(Token, String)> {
-  let capture = pattern.captures(input)?;
-  let full = &capture[0];
-  let token = Token {
-    kind: kind.clone(),
-    value: full.to_string(),
-    location: location.clone(),
+/// Returns the length of the numeral at the start of
+/// `input`, read the way Lua does: a digit, or a dot and a
+/// digit, followed by any letters, digits, dots and signed
+/// exponents. Whether it is well formed is checked after.
+fn numeral_len(input: &str) -> Option<usize> {
+  let mut chars = input.char_indices().peekable();
+  let (_, first) = chars.next()?;
+  let second = input[first.len_utf8()..].chars().next();
+  let starts_numeral = first.is_ascii_digit()
+    || first == '.' && second.is_some_and(|c| c.is_ascii_digit());
+  if !starts_numeral {
+    return None;
+  }
+  // As in llex.c, only `p` marks the exponent of a
+  // hexadecimal numeral, where `e` is a digit.
+  let exponents = if first == '0' && matches!(second, Some('x' | 'X')) {
+    ['p', 'P']
+  } else {
+    ['e', 'E']
   };
-  update_location(&input[..full.len()], location);
-  return Some((token, input[full.len()..].to_string()));
+  let mut prev = first;
+  let mut len = first.len_utf8();
+  while let Some(&(index, char)) = chars.peek() {
+    let is_exponent_sign =
+      (char == '+' || char == '-') && exponents.contains(&prev);
+    if !char.is_ascii_alphanumeric() && char != '_' && char != '.'
+      && !is_exponent_sign
+    {
+      break;
+    }
+    chars.next();
+    prev = char;
+    len = index + char.len_utf8();
+  }
+  Some(len)
 }
 
-fn apply_patterns(
-  patterns: &Patterns,
-  input: &str,
-  location: &mut Location,
-) -> Option<(Token, String)> {
-  for (pattern, kind) in patterns {
-    let capture = match pattern.captures(input) {
-      None => continue,
-      Some(capt) => capt,
-    };
-    let full = &capture[0];
-    let token = Token {
-      kind: kind.clone(),
-      value: full.to_string(),
-      location: location.clone(),
-    };
-    update_location(&input[..full.len()], location);
-    return Some((token, input[full.len()..].to_string()));
+/// Returns the level of the long bracket `[==[` opening
+/// `input`, that is the number of `=` signs in it.
+fn long_bracket_level(input: &str) -> Option<usize> {
+  let rest = input.strip_prefix('[')?;
+  let level = rest.len() - rest.trim_start_matches('=').len();
+  rest[level..].starts_with('[').then_some(level)
+}
+
+/// Returns the length of the long string or comment body
+/// opening `input` with a bracket of `level`, including both
+/// brackets, or `None` if it is never closed.
+fn long_bracket_len(input: &str, level: usize) -> Option<usize> {
+  let close = format!("]{}]", "=".repeat(level));
+  let open = level + 2;
+  input[open..]
+    .find(&close)
+    .map(|index| open + index + close.len())
+}
+
+/// Returns the length of the quoted string opening `input`,
+/// or `None` if a line ends before its closing quote.
+fn short_string_len(input: &str) -> Option<usize> {
+  let bytes = input.as_bytes();
+  let quote = bytes[0];
+  let mut index = 1;
+  while index < bytes.len() {
+    match bytes[index] {
+      b'\\' => {
+        index += 1;
+        // An escaped line break may be `\r\n` or `\n\r`.
+        if let (Some(&first @ (b'\n' | b'\r')), Some(&second)) =
+          (bytes.get(index), bytes.get(index + 1))
+        {
+          if (second == b'\n' || second == b'\r') && second != first {
+            index += 1;
+          }
+        }
+      }
+      b'\n' | b'\r' => return None,
+      byte if byte == quote => return Some(index + 1),
+      _ => {}
+    }
+    index += 1;
   }
   None
 }
 
-pub fn lex(file: &str, mut input: String) -> Option<Tokens> {
-  input = Regex::new("--.*")
-    .unwrap()
-    .replace_all(&input, "")
-    .to_string();
-  let patterns = get_lex_patterns();
-  let mut tokens = vec![];
+/// Walks the input once, keeping a byte offset into it, so
+/// that the text is never copied except into token values.
+struct Lexer<'a> {
+  input: &'a str,
+  offset: usize,
+  location: Location,
+  tokens: Tokens,
+  errors: Vec<LexError>,
+  name: Regex,
+  number: Regex,
+  /// The number of `#for` loops not yet closed by `#endfor`.
+  open_loops: usize,
+}
 
-  let mut location = Location {
-    file: file.to_string(),
-    line: 1,
-    column: 1
-  };
+impl<'a> Lexer<'a> {
+  fn rest(&self) -> &'a str {
+    &self.input[self.offset..]
+  }
 
-  while !input.is_empty() {
-    update_location(
-      &input[..input.len() - input.trim_start().len()],
-      &mut location
-    );
-    input = input.trim_start().to_string();
-    if input.is_empty() {
-      break;
+  fn advance(&mut self, len: usize) {
+    let skipped = &self.input[self.offset..self.offset + len];
+    update_location(skipped, &mut self.location);
+    self.offset += len;
+  }
+
+  /// Returns the current location, spanning the next `len`
+  /// bytes.
+  fn location(&self, len: usize) -> Location {
+    Location {
+      span: Span {
+        start: self.offset as u32,
+        end: (self.offset + len) as u32,
+      },
+      ..self.location.clone()
+    }
+  }
+
+  fn push(&mut self, kind: TokenKind, len: usize) {
+    self.tokens.push(Token {
+      kind,
+      value: self.rest()[..len].into(),
+      location: self.location(len),
+    });
+    self.advance(len);
+  }
+
+  fn error(&mut self, kind: LexErrorKind, len: usize) {
+    self.errors.push(LexError {
+      kind,
+      location: self.location(len),
+    });
+    self.advance(len);
+  }
+
+  /// Skips whitespace and comments, returning whether any
+  /// input is left.
+  fn skip_blank(&mut self) -> bool {
+    loop {
+      let rest = self.rest();
+      self.advance(rest.len() - rest.trim_start().len());
+      let rest = self.rest();
+      let Some(comment) = rest.strip_prefix("--") else {
+        return !rest.is_empty();
+      };
+      match long_bracket_level(comment) {
+        Some(level) => match long_bracket_len(comment, level) {
+          Some(len) => self.advance(len + 2),
+          None => {
+            self.error(LexErrorKind::UnterminatedComment, rest.len())
+          }
+        },
+        None => {
+          self.advance(rest.find(['\n', '\r']).unwrap_or(rest.len()))
+        }
+      }
     }
-    let (token, new_input) = apply_patterns(&patterns, &input, &mut location)?;
-    input = new_input;
-    tokens.push(token);
   }
 
-  Some(tokens)
+  fn numeral(&mut self, len: usize) {
+    let numeral = &self.rest()[..len];
+    if self.number.is_match(numeral) {
+      self.push(TokenKind::Number, len);
+    } else {
+      let kind = LexErrorKind::MalformedNumber(numeral.to_string());
+      self.error(kind, len);
+    }
+  }
+
+  fn string(&mut self) {
+    let rest = self.rest();
+    let len = match long_bracket_level(rest) {
+      Some(level) => long_bracket_len(rest, level),
+      None => short_string_len(rest),
+    };
+    match len {
+      Some(len) => self.push(TokenKind::String, len),
+      None => {
+        let end = if rest.starts_with('[') {
+          rest.len()
+        } else {
+          rest.find(['\n', '\r']).unwrap_or(rest.len())
+        };
+        self.error(LexErrorKind::UnterminatedString, end);
+      }
+    }
+  }
+
+  fn word(&mut self, len: usize) {
+    let rest = self.rest();
+    let word = &rest[..len];
+    let kind = match word {
+      "true" | "false" => TokenKind::Boolean,
+      _ if rest[len..].starts_with('!') => {
+        return self.push(TokenKind::Macro, len + 1);
+      }
+      "nil" => TokenKind::Nil,
+      _ if KEYWORDS.contains(&word) => TokenKind::Keyword,
+      _ => TokenKind::Name,
+    };
+    self.push(kind, len);
+  }
+
+  /// Lexes a `#` followed by a name, which is either a
+  /// directive, a stringified name or the length operator.
+  fn directive(&mut self, len: usize) -> bool {
+    let rest = self.rest();
+    let name = &rest[1..len + 1];
+    if let Some((_, kind)) =
+      DIRECTIVES.iter().find(|(directive, _)| *directive == name)
+    {
+      // `#endfor` only ends a loop if one is open, so that
+      // `x, #endfor` and `in #endfor` still close it.
+      let operand = match kind {
+        TokenKind::EndFor => self.open_loops == 0,
+        _ => STATEMENT_DIRECTIVES.contains(kind),
+      };
+      if !(operand && self.expects_operand()) {
+        match kind {
+          TokenKind::For => self.open_loops += 1,
+          TokenKind::EndFor => {
+            self.open_loops = self.open_loops.saturating_sub(1)
+          }
+          _ => {}
+        }
+        self.push(kind.clone(), len + 1);
+        return true;
+      }
+    }
+    if rest[len + 1..].starts_with('#') {
+      self.push(TokenKind::Stringify, len + 2);
+      return true;
+    }
+    false
+  }
+
+  /// Whether the last token is one that an expression must
+  /// follow, such as a binary operator or `(`.
+  fn expects_operand(&self) -> bool {
+    // The `{` after a `=>` opens the body of a `#rules` rule.
+    let rule_body = self.tokens.len() >= 2
+      && &*self.tokens[self.tokens.len() - 2].value == "=>";
+    self.tokens.last().is_some_and(|token| match token.kind {
+      TokenKind::Operator => {
+        &*token.value != "::" && !self.ends_define_header()
+      }
+      TokenKind::Delimiter => &*token.value == ",",
+      TokenKind::Brace => match &*token.value {
+        "(" | "[" => true,
+        "{" => !rule_body,
+        _ => false,
+      },
+      TokenKind::Keyword => matches!(
+        &*token.value,
+        "return" | "and" | "or" | "not" | "in" | "until" | "if"
+          | "elseif" | "while"
+      ),
+      _ => false,
+    })
+  }
+
+  /// Whether the last token is the `=` that ends the header
+  /// of a `#define`, so that its body starts next.
+  fn ends_define_header(&self) -> bool {
+    let mut tokens = self.tokens.iter().rev();
+    if tokens.next().is_none_or(|token| &*token.value != "=") {
+      return false;
+    }
+    let mut depth = 0;
+    let name = tokens.find(|token| {
+      match &*token.value {
+        ")" => depth += 1,
+        "(" => depth -= 1,
+        _ => return depth == 0,
+      }
+      false
+    });
+    name.is_some_and(|name| name.kind == TokenKind::Macro)
+      && tokens.next().is_some_and(|define| {
+        define.kind == TokenKind::Define
+      })
+  }
+
+  fn symbol(&mut self) {
+    let rest = self.rest();
+    if let Some((symbol, kind)) =
+      SYMBOLS.iter().find(|(symbol, _)| rest.starts_with(symbol))
+    {
+      return self.push(kind.clone(), symbol.len());
+    }
+    let char = rest.chars().next().unwrap();
+    match symbol_kind(char) {
+      Some(kind) => self.push(kind, 1),
+      None => {
+        let kind = LexErrorKind::UnknownCharacter(char);
+        self.error(kind, char.len_utf8());
+      }
+    }
+  }
+
+  fn name_len(&self, input: &str) -> Option<usize> {
+    self.name.find(input).map(|found| found.end())
+  }
+
+  fn next_token(&mut self) {
+    let rest = self.rest();
+    if let Some(len) = numeral_len(rest) {
+      return self.numeral(len);
+    }
+    if rest.starts_with(['"', '\''])
+      || long_bracket_level(rest).is_some()
+    {
+      return self.string();
+    }
+    if let Some(len) = self.name_len(rest) {
+      return self.word(len);
+    }
+    if let Some(len) = rest
+      .strip_prefix('#')
+      .and_then(|directive| self.name_len(directive))
+    {
+      if self.directive(len) {
+        return;
+      }
+    }
+    self.symbol();
+  }
+}
+
+/// Splits `input` into tokens in a single pass, registering
+/// it in the file table under `file`. Lexing goes on after an
+/// invalid token so that every error in the file is reported.
+pub fn lex(file: &str, input: &str) -> Result<Tokens, Vec<LexError>> {
+  let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+  let mut lexer = Lexer {
+    input,
+    offset: 0,
+    location: Location {
+      file: files::add_source(file, input),
+      line: 1,
+      column: 1,
+      utf16_column: 1,
+      ..Location::default()
+    },
+    tokens: vec![],
+    errors: vec![],
+    name: Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*").unwrap(),
+    number: Regex::new(
+      r"^(0[xX]([[:xdigit:]]+\.?[[:xdigit:]]*|\.[[:xdigit:]]+)([pP][+-]?\d+)?|(\d+\.?\d*|\.\d+)([eE][+-]?\d+)?)$",
+    )
+    .unwrap(),
+    open_loops: 0,
+  };
+  while lexer.skip_blank() {
+    lexer.next_token();
+  }
+  if !lexer.errors.is_empty() {
+    return Err(lexer.errors);
+  }
+  Ok(lexer.tokens)
 }
@@ -0,0 +1,172 @@
+use crate::eval::quote;
+use crate::lexer::{self, Location, Token, Tokens};
+use crate::macros::Macros;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// The interpreter `#lua` blocks and `run` use, unless
+/// `--lua` names another.
+pub const DEFAULT_INTERPRETER: &str = "lua5.4";
+
+/// The command line options `#lua` blocks are run with.
+#[derive(Debug, Default)]
+pub struct Options {
+  /// The flags, which blocks can read from `flags`.
+  pub flags: Vec<String>,
+  /// The interpreter given by `--lua`, if any.
+  pub interpreter: Option<String>,
+}
+
+fn table<'a>(entries: impl Iterator<Item = (&'a str, String)>) -> String {
+  let entries = entries
+    .map(|(name, value)| {
+      format!("[{}] = {}", quote(name.as_bytes()), value)
+    })
+    .collect::<Vec<_>>();
+  format!("{{{}}}", entries.join(", "))
+}
+
+/// Defines `emit`, which collects the code the block
+/// generates, and the `macros` and `flags` tables, and sends
+/// `print` to stderr. Header guards, whose names have no
+/// `!`, are left out of `macros`. It is kept on the first
+/// line, so that the block starts on the second.
+fn prelude(macros: &Macros, options: &Options) -> String {
+  let values = macros.value_macros().filter_map(|value_macro| {
+    let body = value_macro
+      .tokens
+      .iter()
+      .map(|token| &*token.value)
+      .collect::<Vec<_>>()
+      .join(" ");
+    Some((value_macro.name.strip_suffix('!')?, quote(body.as_bytes())))
+  });
+  let callables = macros
+    .callable_names()
+    .filter_map(|name| Some((name.strip_suffix('!')?, "true".into())));
+  let flags = options.flags.iter().map(|flag| (&**flag, "true".into()));
+  format!(
+    "local __emitted = {{}} \
+     function emit(...) for i = 1, select('#', ...) do \
+     __emitted[#__emitted + 1] = tostring((select(i, ...))) end end \
+     function print(...) local t = table.pack(...) \
+     for i = 1, t.n do t[i] = tostring(t[i]) end \
+     io.stderr:write(table.concat(t, '\\t'), '\\n') end \
+     macros = {} flags = {} ;(function()\n",
+    table(values.chain(callables)),
+    table(flags),
+  )
+}
+
+/// Writes the tokens of a block back out as Lua, keeping
+/// those read from its file on their lines.
+fn block_source(body: &[Token]) -> String {
+  let mut source = String::new();
+  let Some(first) = body.first() else {
+    return source;
+  };
+  let mut line = first.location.line;
+  for token in body {
+    if token.location.file == first.location.file
+      && token.location.line > line
+    {
+      source += &"\n".repeat((token.location.line - line) as usize);
+      line = token.location.line;
+    } else if !source.is_empty() {
+      source.push(' ');
+    }
+    source += &token.value;
+    line += token.value.matches('\n').count() as i32;
+  }
+  source
+}
+
+/// Builds the chunk that runs a `#lua` block and writes out
+/// what it emits.
+pub fn script(
+  body: &[Token],
+  macros: &Macros,
+  options: &Options,
+) -> String {
+  format!(
+    "{}{}\nend)() io.write(table.concat(__emitted, ' '))\n",
+    prelude(macros, options),
+    block_source(body),
+  )
+}
+
+/// Turns the interpreter's error output into an error at the
+/// line of the block it refers to.
+pub fn block_error(
+  stderr: &str,
+  body: &[Token],
+  directive: &Token,
+) -> String {
+  let message = stderr.lines().next().unwrap_or_default();
+  let located = message.split_once("stdin:").and_then(|(_, rest)| {
+    let (line, message) = rest.split_once(':')?;
+    let offset = line.parse::<i32>().ok()?.checked_sub(2)?;
+    Some((offset, message.trim()))
+  });
+  let (location, message) = match (located, body.first()) {
+    (Some((offset, message)), Some(first)) => (
+      Location {
+        line: first.location.line + offset,
+        column: 1,
+        utf16_column: 1,
+        ..first.location.clone()
+      },
+      message,
+    ),
+    _ => (directive.location.clone(), message),
+  };
+  format!(
+    "{}: Lua error: {}{}",
+    location,
+    message,
+    directive.location.expansion_trace(),
+  )
+}
+
+/// Runs a `#lua` block with the interpreter and returns the
+/// tokens it emitted. What the block prints is passed on to
+/// stderr.
+pub fn run_block(
+  body: &[Token],
+  directive: &Token,
+  macros: &Macros,
+  options: &Options,
+) -> Result<Tokens, String> {
+  let interpreter =
+    options.interpreter.as_deref().unwrap_or(DEFAULT_INTERPRETER);
+  let cannot_run = |error: std::io::Error| {
+    format!(
+      "{}: Could not run `{}` for `#lua`: {}{}",
+      directive.location,
+      interpreter,
+      error,
+      directive.location.expansion_trace(),
+    )
+  };
+  let mut child = Command::new(interpreter)
+    .arg("-")
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .map_err(cannot_run)?;
+  if let Some(mut stdin) = child.stdin.take() {
+    stdin
+      .write_all(script(body, macros, options).as_bytes())
+      .map_err(cannot_run)?;
+  }
+  let output = child.wait_with_output().map_err(cannot_run)?;
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  if !output.status.success() {
+    return Err(block_error(&stderr, body, directive));
+  }
+  eprint!("{}", stderr);
+  let emitted = String::from_utf8_lossy(&output.stdout);
+  let name = format!("<#lua at {}>", directive.location);
+  lexer::lex(&name, &emitted).map_err(lexer::join_errors)
+}
@@ -0,0 +1,422 @@
+use crate::files::FileId;
+use crate::lexer::{Location, Tokens};
+use crate::rules::RulesMacro;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+#[derive(Debug)]
+pub struct ValueMacro {
+  pub name: String,
+  pub tokens: Tokens,
+}
+
+#[derive(Debug)]
+pub struct Param {
+  pub name: String,
+  /// Substituted when the invocation leaves the argument out.
+  pub default: Option<Tokens>,
+}
+
+#[derive(Debug)]
+pub struct FuncMacro {
+  pub name: String,
+  pub params: Vec<Param>,
+  /// The separator declared before each parameter after the
+  /// first, and before `#...`.
+  pub separators: Vec<String>,
+  pub vararg: bool,
+  pub tokens: Tokens,
+  pub location: Location,
+}
+
+impl FuncMacro {
+  pub fn param_names(&self) -> Vec<String> {
+    self.params.iter().map(|param| param.name.clone()).collect()
+  }
+
+  /// Whether an invocation may pass `count` arguments.
+  pub fn accepts(&self, count: usize) -> bool {
+    let required = self
+      .params
+      .iter()
+      .filter(|param| param.default.is_none())
+      .count();
+    count >= required && (self.vararg || count <= self.params.len())
+  }
+
+  /// Renders the parameter list, as in `log!(msg, level = ...)`.
+  pub fn signature(&self) -> String {
+    let mut params = self
+      .params
+      .iter()
+      .map(|param| match param.default {
+        Some(_) => format!("{} = ...", param.name),
+        None => param.name.clone(),
+      })
+      .collect::<Vec<_>>();
+    if self.vararg {
+      params.push("#...".to_string());
+    }
+    let mut signature = format!("{}(", self.name);
+    for (i, param) in params.iter().enumerate() {
+      match i.checked_sub(1).and_then(|i| self.separators.get(i)) {
+        Some(separator) if separator != "," => {
+          signature += &format!(" {} ", separator);
+        }
+        Some(_) => signature += ", ",
+        None => {}
+      }
+      signature += param;
+    }
+    signature + ")"
+  }
+}
+
+/// Bounds on macro expansion, so that a runaway macro fails
+/// with an error instead of exhausting the stack or memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+  /// How deeply expansions may nest.
+  pub max_depth: usize,
+  /// How many expansions a translation unit may perform.
+  pub max_expansions: usize,
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Limits {
+      max_depth: 256,
+      max_expansions: 1_000_000,
+    }
+  }
+}
+
+/// Macro definitions by name. Definitions are shared, so
+/// that saving them for `#push_macro` or `#scope` never
+/// copies a body.
+#[derive(Debug, Default, Clone)]
+pub struct Definitions {
+  values: HashMap<String, Rc<ValueMacro>>,
+  /// The overloads of each function macro, which differ in
+  /// their number of parameters.
+  funcs: HashMap<String, Vec<Rc<FuncMacro>>>,
+  rules: HashMap<String, Rc<RulesMacro>>,
+  /// The file that first defined each macro.
+  owners: HashMap<String, FileId>,
+  /// The macros marked `#private`, which only their own file
+  /// can invoke.
+  private: HashSet<String>,
+}
+
+impl Definitions {
+  /// The definitions of `name` alone.
+  fn of(&self, name: &str) -> Definitions {
+    let mut definitions = Definitions::default();
+    if let Some(value_macro) = self.values.get(name) {
+      definitions.values.insert(name.to_string(), value_macro.clone());
+    }
+    if let Some(overloads) = self.funcs.get(name) {
+      definitions.funcs.insert(name.to_string(), overloads.clone());
+    }
+    if let Some(rules_macro) = self.rules.get(name) {
+      definitions.rules.insert(name.to_string(), rules_macro.clone());
+    }
+    if let Some(owner) = self.owners.get(name) {
+      definitions.owners.insert(name.to_string(), *owner);
+    }
+    if self.private.contains(name) {
+      definitions.private.insert(name.to_string());
+    }
+    definitions
+  }
+}
+
+/// The macros defined so far, looked up by name.
+#[derive(Debug, Default)]
+pub struct Macros {
+  definitions: Definitions,
+  /// The definitions saved by `#push_macro`, most recent
+  /// last.
+  pushed: HashMap<String, Vec<Definitions>>,
+  /// Every namespace a `#namespace` block has declared,
+  /// including those it is nested in.
+  namespaces: HashSet<String>,
+  /// The `#namespace` blocks being expanded, outermost first.
+  namespace: Vec<String>,
+  /// The namespaces each file has brought into scope with
+  /// `#using`.
+  usings: HashMap<FileId, Vec<String>>,
+  /// The macros each file that uses `#export` exports. Its
+  /// other macros are private.
+  exports: HashMap<FileId, HashSet<String>>,
+  pub limits: Limits,
+  expansions: usize,
+  /// How many macro bodies are being expanded, one inside
+  /// the other.
+  depth: usize,
+}
+
+impl Macros {
+  /// Defines a value macro unless one of that name already
+  /// exists, in which case the first definition is kept.
+  pub fn define_value(&mut self, value_macro: ValueMacro) {
+    self
+      .definitions
+      .values
+      .entry(value_macro.name.clone())
+      .or_insert_with(|| Rc::new(value_macro));
+  }
+
+  /// Adds an overload of a function macro, unless one with
+  /// the same parameter count exists, in which case the first
+  /// definition is kept.
+  pub fn define_func(&mut self, func_macro: FuncMacro) {
+    let overloads = self
+      .definitions
+      .funcs
+      .entry(func_macro.name.clone())
+      .or_default();
+    if !overloads.iter().any(|overload| {
+      overload.params.len() == func_macro.params.len()
+        && overload.vararg == func_macro.vararg
+    }) {
+      overloads.push(Rc::new(func_macro));
+    }
+  }
+
+  /// Defines a `#rules` macro unless one of that name
+  /// already exists, in which case the first definition is
+  /// kept.
+  pub fn define_rules(&mut self, rules_macro: RulesMacro) {
+    self
+      .definitions
+      .rules
+      .entry(rules_macro.name.clone())
+      .or_insert_with(|| Rc::new(rules_macro));
+  }
+
+  pub fn undefine(&mut self, name: &str) {
+    self.definitions.values.remove(name);
+    self.definitions.funcs.remove(name);
+    self.definitions.rules.remove(name);
+    self.definitions.owners.remove(name);
+    self.definitions.private.remove(name);
+  }
+
+  pub fn is_defined(&self, name: &str) -> bool {
+    self.definitions.values.contains_key(name)
+      || self.definitions.funcs.contains_key(name)
+      || self.definitions.rules.contains_key(name)
+  }
+
+  pub fn value(&self, name: &str) -> Option<Rc<ValueMacro>> {
+    self.definitions.values.get(name).cloned()
+  }
+
+  pub fn rules(&self, name: &str) -> Option<Rc<RulesMacro>> {
+    self.definitions.rules.get(name).cloned()
+  }
+
+  pub fn value_macros(&self) -> impl Iterator<Item = &Rc<ValueMacro>> {
+    self.definitions.values.values()
+  }
+
+  /// The names of the function and rules macros.
+  pub fn callable_names(&self) -> impl Iterator<Item = &String> {
+    let Definitions { funcs, rules, .. } = &self.definitions;
+    funcs.keys().chain(rules.keys())
+  }
+
+  pub fn overloads(&self, name: &str) -> Vec<Rc<FuncMacro>> {
+    self.definitions.funcs.get(name).cloned().unwrap_or_default()
+  }
+
+  /// Picks the overload of `name` to invoke with `count`
+  /// arguments: the non-variadic one with the fewest
+  /// parameters that takes them, or else the variadic one
+  /// with the most.
+  pub fn func(&self, name: &str, count: usize) -> Option<Rc<FuncMacro>> {
+    let overloads = self.definitions.funcs.get(name)?;
+    let accepting = || {
+      overloads.iter().filter(|overload| overload.accepts(count))
+    };
+    accepting()
+      .filter(|overload| !overload.vararg)
+      .min_by_key(|overload| overload.params.len())
+      .or_else(|| accepting().max_by_key(|overload| overload.params.len()))
+      .cloned()
+  }
+
+  /// Saves the definition of `name`, which `pop_macro`
+  /// restores.
+  pub fn push_macro(&mut self, name: &str) {
+    let saved = self.definitions.of(name);
+    self.pushed.entry(name.to_string()).or_default().push(saved);
+  }
+
+  /// Restores the definition of `name` that `push_macro` last
+  /// saved, returning whether there was one.
+  pub fn pop_macro(&mut self, name: &str) -> bool {
+    let Some(saved) = self.pushed.get_mut(name).and_then(Vec::pop) else {
+      return false;
+    };
+    self.undefine(name);
+    self.definitions.values.extend(saved.values);
+    self.definitions.funcs.extend(saved.funcs);
+    self.definitions.rules.extend(saved.rules);
+    self.definitions.owners.extend(saved.owners);
+    self.definitions.private.extend(saved.private);
+    true
+  }
+
+  /// Every definition, for `restore` to bring back at the end
+  /// of a `#scope`.
+  pub fn definitions(&self) -> Definitions {
+    self.definitions.clone()
+  }
+
+  pub fn restore(&mut self, definitions: Definitions) {
+    self.definitions = definitions;
+  }
+
+  /// Records that `file` defines `name`, unless it is
+  /// already defined, in which case the first definition is
+  /// kept.
+  pub fn set_owner(&mut self, name: &str, file: FileId, private: bool) {
+    if self.is_defined(name) {
+      return;
+    }
+    self.definitions.owners.insert(name.to_string(), file);
+    if private {
+      self.definitions.private.insert(name.to_string());
+    }
+  }
+
+  /// Makes `name` visible outside of `file`, and the macros
+  /// of `file` that are not exported private.
+  pub fn export(&mut self, file: FileId, name: String) {
+    self.exports.entry(file).or_default().insert(name);
+  }
+
+  /// The file `name` is private to, if it cannot be invoked
+  /// from `file`.
+  pub fn private_owner(&self, name: &str, file: FileId) -> Option<FileId> {
+    let owner = *self.definitions.owners.get(name)?;
+    let private = self.definitions.private.contains(name)
+      || self
+        .exports
+        .get(&owner)
+        .is_some_and(|exports| !exports.contains(name));
+    Some(owner).filter(|owner| private && *owner != file)
+  }
+
+  /// Starts a `#namespace` block, which may be nested in
+  /// others.
+  pub fn enter_namespace(&mut self, name: &str) {
+    self.namespace.push(name.to_string());
+    let path = self.namespace();
+    let mut prefix = String::new();
+    for part in path.split('.') {
+      if !prefix.is_empty() {
+        prefix.push('.');
+      }
+      prefix += part;
+      self.namespaces.insert(prefix.clone());
+    }
+  }
+
+  pub fn leave_namespace(&mut self) {
+    self.namespace.pop();
+  }
+
+  /// The path of the `#namespace` blocks being expanded, or
+  /// an empty string outside of them.
+  pub fn namespace(&self) -> String {
+    self.namespace.join(".")
+  }
+
+  pub fn is_namespace(&self, path: &str) -> bool {
+    self.namespaces.contains(path)
+  }
+
+  /// The name a macro defined as `name` gets in the current
+  /// namespace. Header guards, whose names have no `!`, stay
+  /// global.
+  pub fn qualify(&self, name: &str) -> String {
+    if self.namespace.is_empty() || !name.ends_with('!') {
+      name.to_string()
+    } else {
+      format!("{}.{}", self.namespace(), name)
+    }
+  }
+
+  /// Brings the macros of `namespace` into scope for `file`.
+  pub fn use_namespace(&mut self, file: FileId, namespace: String) {
+    let usings = self.usings.entry(file).or_default();
+    if !usings.contains(&namespace) {
+      usings.push(namespace);
+    }
+  }
+
+  /// Finds the macro `name` refers to when written in
+  /// `namespace` and `file`: one in that namespace or an
+  /// enclosing one, or else the one global or `#using`
+  /// macro of that name. If there are several of the latter,
+  /// they are returned as the error.
+  pub fn resolve(
+    &self,
+    name: &str,
+    namespace: &str,
+    file: FileId,
+  ) -> Result<String, Vec<String>> {
+    if name.contains('.') {
+      return Ok(name.to_string());
+    }
+    let mut enclosing = Some(namespace).filter(|path| !path.is_empty());
+    while let Some(path) = enclosing {
+      let qualified = format!("{}.{}", path, name);
+      if self.is_defined(&qualified) {
+        return Ok(qualified);
+      }
+      enclosing = path.rsplit_once('.').map(|(outer, _)| outer);
+    }
+    let mut candidates = self
+      .usings
+      .get(&file)
+      .into_iter()
+      .flatten()
+      .map(|path| format!("{}.{}", path, name))
+      .filter(|qualified| self.is_defined(qualified))
+      .collect::<Vec<_>>();
+    if self.is_defined(name) {
+      candidates.insert(0, name.to_string());
+    }
+    match candidates.len() {
+      0 => Ok(name.to_string()),
+      1 => Ok(candidates.remove(0)),
+      _ => Err(candidates),
+    }
+  }
+
+  /// Starts expanding a macro body inside those being
+  /// expanded, returning whether it stays within
+  /// `limits.max_depth`.
+  pub fn enter_expansion(&mut self) -> bool {
+    if self.depth >= self.limits.max_depth {
+      return false;
+    }
+    self.depth += 1;
+    true
+  }
+
+  pub fn leave_expansion(&mut self) {
+    self.depth -= 1;
+  }
+
+  /// Counts one more expansion, returning whether it stays
+  /// within `limits.max_expansions`.
+  pub fn count_expansion(&mut self) -> bool {
+    self.expansions += 1;
+    self.expansions <= self.limits.max_expansions
+  }
+}
@@ -1,24 +1,57 @@
+use macros::{FuncMacro, Limits, Macros, Param, ValueMacro};
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::process::exit;
 use std::process::Command;
 use std::process::Stdio;
+use std::rc::Rc;
 use std::{iter::Peekable, vec::IntoIter};
+mod eval;
+mod format;
+mod files;
+mod hygiene;
 mod lexer;
+mod lua;
+mod macros;
+mod minify;
+mod parser;
+mod rules;
+#[cfg(test)]
+mod tests;
 
-#[derive(Debug, Clone)]
-struct ValueMacro {
-  name: String,
+/// A position in a token list. Looking ahead only reads the
+/// list, where peeking a cloned iterator would copy the rest
+/// of it.
+struct Cursor {
   tokens: lexer::Tokens,
+  index: usize,
 }
 
-#[derive(Debug, Clone)]
-struct FuncMacro {
-  name: String,
-  params: Vec<String>,
-  vararg: bool,
-  tokens: lexer::Tokens,
+impl Cursor {
+  fn new(tokens: lexer::Tokens) -> Self {
+    Cursor { tokens, index: 0 }
+  }
+
+  fn peek(&self) -> Option<&lexer::Token> {
+    self.tokens.get(self.index)
+  }
+
+  fn next_if_value(&mut self, value: &str) -> Option<lexer::Token> {
+    self.peek().filter(|token| &*token.value == value)?;
+    self.next()
+  }
+}
+
+impl Iterator for Cursor {
+  type Item = lexer::Token;
+
+  fn next(&mut self) -> Option<lexer::Token> {
+    let token = self.tokens.get(self.index)?.clone();
+    self.index += 1;
+    Some(token)
+  }
 }
 
 fn replace_tokens(
@@ -26,49 +59,158 @@ fn replace_tokens(
   old: lexer::Token,
   new: lexer::Tokens,
 ) -> lexer::Tokens {
-  let mut new_tokens = Vec::new();
-  let mut iter = tokens.into_iter().peekable();
-
-  while let Some(token) = iter.clone().peek() {
+  let mut new_tokens = Vec::with_capacity(tokens.len());
+  for token in tokens {
     if token.kind == old.kind && token.value == old.value {
-      iter.next();
-      new_tokens.extend(new.clone().into_iter()
-        .map(|tok| lexer::Token {
-          kind: tok.kind,
-          value: tok.value,
-          location: token.location.clone(),
-        })
-        .collect::<Vec<_>>()
-        .clone());
+      new_tokens.extend(new.iter().cloned());
     } else if token.kind == lexer::TokenKind::Stringify
-      && &token.value[1..token.value.len() - 1]
-        == old.value.as_str()
+      && token.value[1..token.value.len() - 1] == *old.value
     {
-      iter.next();
       new_tokens.push(lexer::Token {
         kind: lexer::TokenKind::String,
-        value: format!("{:?}", render_tokens(new.clone())),
-        location: token.clone().location,
+        value: format!("{:?}", render_tokens(new.clone())).into(),
+        location: token.location,
       });
     } else {
-      new_tokens.push(iter.next().unwrap());
+      new_tokens.push(token);
     }
   }
-
   new_tokens
 }
 
-fn get_macro_body(
-  iter: &mut Peekable<IntoIter<lexer::Token>>,
+type Bindings<'a> = HashMap<&'a str, lexer::Tokens>;
+
+/// Whether `arg` has the form `name = ...`, returning the
+/// name and the tokens after the `=`.
+fn split_named_arg(arg: &[lexer::Token]) -> Option<(&str, &[lexer::Token])> {
+  match arg {
+    [name, eq, value @ ..]
+      if name.kind == lexer::TokenKind::Name && &*eq.value == "=" =>
+    {
+      Some((&name.value, value))
+    }
+    _ => None,
+  }
+}
+
+/// Pairs the parameters of `func_macro` with the arguments
+/// of `invocation`, and returns the arguments left over as
+/// varargs. An argument `name = ...` binds the parameter of
+/// that name, if there is one; otherwise it is a positional
+/// argument like any other, which must come before the named
+/// ones. A parameter whose argument is left out or empty
+/// takes its default.
+fn bind_args<'a>(
+  func_macro: &'a FuncMacro,
+  args: Vec<lexer::Tokens>,
+  invocation: &lexer::Token,
+) -> Result<(Bindings<'a>, Vec<lexer::Tokens>), String> {
+  let trace = invocation.location.expansion_trace();
+  let mut positional = vec![];
+  let mut named = HashMap::new();
+  for arg in args {
+    let split = split_named_arg(&arg);
+    let param = split.and_then(|(name, _)| {
+      func_macro.params.iter().find(|param| param.name == name)
+    });
+    if let (Some(param), Some((_, value))) = (param, split) {
+      if named.insert(param.name.as_str(), value.to_vec()).is_some() {
+        return Err(format!(
+          "{}: Argument `{}` is given more than once{}",
+          arg[0].location, param.name, trace,
+        ));
+      }
+    } else if named.is_empty() {
+      positional.push(arg);
+    } else {
+      let location = arg.first().unwrap_or(invocation).location.clone();
+      return Err(match split {
+        Some((name, _)) => format!(
+          "{}: `{}` has no parameter `{}`{}",
+          location, invocation.value, name, trace,
+        ),
+        None => format!(
+          "{}: Positional argument after named arguments{}",
+          location, trace,
+        ),
+      });
+    }
+  }
+  let mut positional = positional.into_iter();
+  let mut bindings = HashMap::new();
+  for param in &func_macro.params {
+    let name = param.name.as_str();
+    let arg = match (positional.next(), named.remove(name)) {
+      (Some(_), Some(_)) => {
+        return Err(format!(
+          "{}: Argument `{}` is given more than once{}",
+          invocation.location, name, trace,
+        ))
+      }
+      (arg, named) => arg.or(named),
+    };
+    let arg = arg.filter(|arg| !arg.is_empty() || param.default.is_none());
+    let arg = match (arg, &param.default) {
+      (Some(arg), _) => arg,
+      (None, Some(default)) => default.clone(),
+      (None, None) => {
+        return Err(format!(
+          "{}: Missing argument `{}` in invocation of `{}`{}",
+          invocation.location, name, invocation.value, trace,
+        ))
+      }
+    };
+    bindings.insert(name, arg);
+  }
+  let rest = positional.filter(|arg| !arg.is_empty()).collect();
+  Ok((bindings, rest))
+}
+
+/// Replaces every parameter in `body` by its argument, and
+/// every `#param#` by its argument as a string, in one pass.
+/// Arguments keep their locations, so they are not mistaken
+/// for tokens of the macro body.
+fn substitute(
+  body: lexer::Tokens,
+  bindings: &Bindings,
 ) -> lexer::Tokens {
+  let mut new_tokens = Vec::with_capacity(body.len());
+  for token in body {
+    let arg = match token.kind {
+      lexer::TokenKind::Name => bindings.get(&*token.value),
+      lexer::TokenKind::Stringify => {
+        bindings.get(&token.value[1..token.value.len() - 1])
+      }
+      _ => None,
+    };
+    let Some(arg) = arg else {
+      new_tokens.push(token);
+      continue;
+    };
+    if token.kind == lexer::TokenKind::Stringify {
+      new_tokens.push(lexer::Token {
+        kind: lexer::TokenKind::String,
+        value: format!("{:?}", render_tokens(arg.clone())).into(),
+        location: token.location,
+      });
+      continue;
+    }
+    new_tokens.extend(arg.iter().cloned());
+  }
+  new_tokens
+}
+
+fn get_macro_body(iter: &mut Cursor) -> lexer::Tokens {
   let mut tokens = vec![];
   while let Some(token) = iter.next() {
-    if token.kind == lexer::TokenKind::Define {
+    if token.kind == lexer::TokenKind::Define
+      || token.kind == lexer::TokenKind::Rules
+    {
       let new_tokens = get_macro_body(iter);
       tokens.push(token);
       tokens.extend(new_tokens);
     } else if token.kind == lexer::TokenKind::EndDefine {
-      return tokens
+      return tokens;
     } else {
       tokens.push(token);
     }
@@ -77,31 +219,61 @@ fn get_macro_body(
 }
 
 fn process_value_macro(
-  iter: &mut Peekable<IntoIter<lexer::Token>>,
-  value_macros: &mut Vec<ValueMacro>,
+  iter: &mut Cursor,
+  macros: &mut Macros,
   name: lexer::Token,
 ) -> Option<()> {
   let value = get_macro_body(iter);
-  value_macros.push(ValueMacro {
-    name: name.value,
+  macros.define_value(ValueMacro {
+    name: name.value.to_string(),
     tokens: value,
   });
   Some(())
 }
 
+/// Whether `token` separates macro arguments. Besides the
+/// delimiters, the separators `declared` by the invoked
+/// macro read as separators inside its argument lists only,
+/// so they stay valid Lua.
+fn is_separator(token: &lexer::Token, declared: &[String]) -> bool {
+  token.kind == lexer::TokenKind::Delimiter
+    || declared.iter().any(|separator| **separator == *token.value)
+}
+
+/// Parses a parameter name and its default value, if it is
+/// followed by `=`.
+fn parse_func_param(
+  iter: &mut Cursor,
+  name: lexer::Token,
+) -> Option<Param> {
+  let mut default = None;
+  if iter.peek().is_some_and(|eq| &*eq.value == "=") {
+    iter.next();
+    default =
+      Some(parse_func_arg(iter, &[]).filter(|arg| !arg.is_empty())?);
+  }
+  Some(Param {
+    name: name.value.to_string(),
+    default,
+  })
+}
+
+/// Parses the parameters after the first, each preceded by
+/// the separator invocations must write before its argument.
 fn parse_func_params_rest(
-  iter: &mut Peekable<IntoIter<lexer::Token>>,
-  args: &mut Vec<String>,
+  iter: &mut Cursor,
+  params: &mut Vec<Param>,
+  separators: &mut Vec<String>,
 ) -> Option<bool> {
   while let Some(next_token) = iter.peek() {
-    if next_token.value.as_str() == ")" {
+    if &*next_token.value == ")" {
       iter.next();
       break;
     }
-    if next_token.kind != lexer::TokenKind::Delimiter {
-      return None;
-    }
-    iter.next()?;
+    let separator = iter
+      .next()
+      .filter(|separator| separator.kind != lexer::TokenKind::Brace)?;
+    separators.push(separator.value.to_string());
     if let Some(name_token) = iter.next() {
       if name_token.kind == lexer::TokenKind::Vararg {
         iter.next()?;
@@ -109,16 +281,19 @@ fn parse_func_params_rest(
       } else if name_token.kind != lexer::TokenKind::Name {
         return None;
       }
-      args.push(name_token.value);
+      params.push(parse_func_param(iter, name_token)?);
     }
   }
   Some(false)
 }
 
-fn parse_func_params(
-  iter: &mut Peekable<IntoIter<lexer::Token>>,
-) -> Option<(Vec<String>, bool)> {
-  let mut args = vec![];
+/// The parameters of a function macro, the separators
+/// declared between them, and whether it takes varargs.
+type FuncParams = (Vec<Param>, Vec<String>, bool);
+
+fn parse_func_params(iter: &mut Cursor) -> Option<FuncParams> {
+  let mut params = vec![];
+  let mut separators = vec![];
   let vararg = iter
     .peek()
     .filter(|var| var.kind == lexer::TokenKind::Vararg);
@@ -127,118 +302,218 @@ fn parse_func_params(
       let name = iter
         .next()
         .filter(|name| name.kind == lexer::TokenKind::Name)?;
-      args.push(name.clone().value);
-      let vararg = parse_func_params_rest(iter, &mut args)?;
-      Some((args, vararg))
+      params.push(parse_func_param(iter, name)?);
+      let vararg =
+        parse_func_params_rest(iter, &mut params, &mut separators)?;
+      Some((params, separators, vararg))
     }
     Some(_) => {
       iter.next();
       iter.next().filter(|paren|
-        paren.value.as_str() == ")"
+        &*paren.value == ")"
       )?;
-      Some((args, true))
+      Some((params, separators, true))
     },
   }
 }
 
 fn process_func_macro(
-  iter: &mut Peekable<IntoIter<lexer::Token>>,
-  func_macros: &mut Vec<FuncMacro>,
+  iter: &mut Cursor,
+  macros: &mut Macros,
   name: lexer::Token,
 ) -> Option<()> {
-  let (params, vararg) = parse_func_params(iter)?;
+  let (params, separators, vararg) = parse_func_params(iter)?;
   let eq_or_end = iter.next()?;
-  match eq_or_end.value.as_str() {
+  match &*eq_or_end.value {
     "=" => {}
     "#end" => {
-      func_macros.push(FuncMacro {
-        name: name.value,
-        params: params,
-        vararg: vararg,
+      macros.define_func(FuncMacro {
+        name: name.value.to_string(),
+        params,
+        separators,
+        vararg,
         tokens: vec![],
+        location: name.location,
       });
       return Some(());
     }
     _ => return None,
   }
   let value = get_macro_body(iter);
-  func_macros.push(FuncMacro {
-    name: name.value,
-    params: params,
-    vararg: vararg,
+  macros.define_func(FuncMacro {
+    name: name.value.to_string(),
+    params,
+    separators,
+    vararg,
     tokens: value,
+    location: name.location,
   });
   Some(())
 }
 
 fn parse_func_arg(
-  iter: &mut Peekable<IntoIter<lexer::Token>>,
+  iter: &mut Cursor,
+  declared: &[String],
 ) -> Option<lexer::Tokens> {
   let mut arg_tokens = vec![];
   let mut nesting_level = 0;
-  let nesters = vec!["(", "[", "{"];
-  let denesters = vec![")", "]", "}"];
-  while let Some(token) = iter.clone().peek() {
+  let nesters = ["(", "[", "{"];
+  let denesters = [")", "]", "}"];
+  while let Some(token) = iter.peek() {
     if nesting_level <= 0
-      && token.kind == lexer::TokenKind::Delimiter
+      && is_separator(token, declared)
     {
       return Some(arg_tokens);
     }
-    if nesters.contains(&token.value.as_str()) {
+    if nesters.contains(&&*token.value) {
       nesting_level += 1;
     }
-    if denesters.contains(&token.value.as_str()) {
+    if denesters.contains(&&*token.value) {
       nesting_level -= 1;
       if nesting_level < 0 {
         return Some(arg_tokens);
       }
     }
-    iter.next()?;
-    arg_tokens.push(token.clone());
+    arg_tokens.push(iter.next()?);
   }
   None
 }
 
+/// Parses the arguments of an invocation, along with the
+/// separator written before each argument after the first.
 fn parse_func_args(
-  iter: &mut Peekable<IntoIter<lexer::Token>>,
-) -> Option<Vec<Vec<lexer::Token>>> {
+  iter: &mut Cursor,
+  declared: &[String],
+) -> Option<(Vec<lexer::Tokens>, lexer::Tokens)> {
   iter.next().filter(|lparen|
-    lparen.value.as_str() == "("
-    || lparen.value.as_str() == "["
-    || lparen.value.as_str() == "{"
+    &*lparen.value == "("
+    || &*lparen.value == "["
+    || &*lparen.value == "{"
   )?;
   let mut args = vec![];
-  while let Some(token) = iter.clone().peek() {
-    if token.value.as_str() == ")"
-      || token.value.as_str() == "]"
-      || token.value.as_str() == "}"
+  let mut separators = vec![];
+  while let Some(token) = iter.peek() {
+    if &*token.value == ")"
+      || &*token.value == "]"
+      || &*token.value == "}"
     {
       iter.next();
       break;
     }
-    if token.kind == lexer::TokenKind::Delimiter {
-      iter.next()?;
+    if is_separator(token, declared) {
+      let separator = iter.next()?;
+      if !args.is_empty() {
+        separators.push(separator);
+      }
     }
-    let arg = parse_func_arg(iter)?;
+    let arg = parse_func_arg(iter, declared)?;
     args.push(arg);
   }
-  Some(args)
+  Some((args, separators))
+}
+
+fn is_conditional(kind: &lexer::TokenKind) -> bool {
+  matches!(
+    kind,
+    lexer::TokenKind::Ifdef | lexer::TokenKind::Ifndef | lexer::TokenKind::If
+  )
 }
 
 fn skip_nested_ifdefs(
-  iter: &mut Peekable<IntoIter<lexer::Token>>,
+  iter: &mut Cursor,
   body: &mut Vec<lexer::Token>,
 ) {
-  let mut inner_body = vec![];
   while let Some(inner_token) = iter.next() {
-    inner_body.push(inner_token.clone());
-    if inner_token.kind == lexer::TokenKind::Ifdef {
-      skip_nested_ifdefs(iter, &mut inner_body);
-    } else if inner_token.kind == lexer::TokenKind::Endif {
+    let kind = inner_token.kind.clone();
+    body.push(inner_token);
+    if is_conditional(&kind) {
+      skip_nested_ifdefs(iter, body);
+    } else if kind == lexer::TokenKind::Endif {
       break;
     }
   }
-  body.extend(inner_body);
+}
+
+/// Takes the condition of `#if`: a parenthesized expression
+/// or a macro invocation, after any number of `not`s.
+fn parse_condition(
+  iter: &mut Cursor,
+  directive: &lexer::Token,
+) -> Result<lexer::Tokens, String> {
+  let start = iter.index;
+  while iter.next_if_value("not").is_some() {}
+  let error = || {
+    format!(
+      "{}: Expected a parenthesized condition or a macro invocation \
+       after `#if`",
+      directive.location,
+    )
+  };
+  match iter.peek() {
+    Some(token) if token.kind == lexer::TokenKind::Macro => {
+      iter.next();
+      if iter.peek().is_some_and(|token| {
+        matches!(&*token.value, "(" | "[" | "{")
+      }) {
+        bracketed(iter).ok_or_else(error)?;
+      }
+    }
+    Some(token) if &*token.value == "(" => {
+      bracketed(iter).ok_or_else(error)?;
+    }
+    _ => return Err(error()),
+  }
+  Ok(iter.tokens[start..iter.index].to_vec())
+}
+
+/// The builtins that describe the tokens they are given, as
+/// written, so that macros can tell what their arguments are.
+const INSPECTORS: [&str; 6] = [
+  "is_name!",
+  "is_string!",
+  "is_number!",
+  "is_empty!",
+  "is_table!",
+  "token_count!",
+];
+
+fn inspect_tokens(
+  inspector: &lexer::Token,
+  tokens: &[lexer::Token],
+) -> lexer::Token {
+  use lexer::TokenKind;
+  let is_one = |kind: TokenKind| {
+    matches!(tokens, [token] if token.kind == kind)
+  };
+  let result = match &*inspector.value {
+    "is_name!" => is_one(TokenKind::Name),
+    "is_string!" => is_one(TokenKind::String),
+    "is_number!" => match tokens {
+      [minus, number] => {
+        &*minus.value == "-" && number.kind == TokenKind::Number
+      }
+      _ => is_one(TokenKind::Number),
+    },
+    "is_empty!" => tokens.is_empty(),
+    "is_table!" => {
+      let mut iter = Cursor::new(tokens.to_vec());
+      iter.peek().is_some_and(|brace| &*brace.value == "{")
+        && bracketed(&mut iter).is_some()
+        && iter.peek().is_none()
+    }
+    _ => {
+      return lexer::Token {
+        kind: TokenKind::Number,
+        value: tokens.len().to_string().into(),
+        location: inspector.location.clone(),
+      }
+    }
+  };
+  lexer::Token {
+    kind: TokenKind::Boolean,
+    value: result.to_string().into(),
+    location: inspector.location.clone(),
+  }
 }
 
 fn apply_bang_pastes(tokens: lexer::Tokens) -> lexer::Tokens {
@@ -248,11 +523,20 @@ fn apply_bang_pastes(tokens: lexer::Tokens) -> lexer::Tokens {
     if token.kind == lexer::TokenKind::Name {
       if let Some(next_token) = iter.peek() {
         if next_token.kind == lexer::TokenKind::Bang {
-          iter.next();
+          // The name may come from an argument, so the macro
+          // takes the expansion of the `##!`, which keeps the
+          // macros it is invoked from in its trace.
+          let bang = iter.next().unwrap();
           new_tokens.push(lexer::Token {
             kind: lexer::TokenKind::Macro,
-            value: (token.value + "!").to_string(),
-            location: token.location,
+            value: format!("{}!", token.value).into(),
+            location: lexer::Location {
+              expansion: bang
+                .location
+                .expansion
+                .or(token.location.expansion.clone()),
+              ..token.location
+            },
           });
         } else {
           new_tokens.push(token);
@@ -276,12 +560,8 @@ fn join_by_commas(tokens: Vec<lexer::Tokens>) -> lexer::Tokens {
     if i > 0 {
       new.push(lexer::Token {
         kind: lexer::TokenKind::Delimiter,
-        value: ",".to_string(),
-        location: lexer::Location {
-          file: "".to_string(),
-          line: 0,
-          column: 0,
-        },
+        value: ",".into(),
+        location: lexer::Location::default(),
       });
     }
     new.extend(token_list);
@@ -289,36 +569,599 @@ fn join_by_commas(tokens: Vec<lexer::Tokens>) -> lexer::Tokens {
   new
 }
 
+/// Takes the tokens between an opening bracket and the one
+/// that closes it.
+fn bracketed(iter: &mut Cursor) -> Option<lexer::Tokens> {
+  iter.next().filter(|lparen| {
+    matches!(&*lparen.value, "(" | "[" | "{")
+  })?;
+  let mut tokens = vec![];
+  let mut depth = 0;
+  for token in iter.by_ref() {
+    match &*token.value {
+      "(" | "[" | "{" => depth += 1,
+      ")" | "]" | "}" if depth == 0 => return Some(tokens),
+      ")" | "]" | "}" => depth -= 1,
+      _ => {}
+    }
+    tokens.push(token);
+  }
+  None
+}
+
+/// Parses the names in `#for [index,] name in`.
+fn parse_for_header(
+  iter: &mut Cursor,
+  directive: &lexer::Token,
+) -> Result<(Option<lexer::Token>, lexer::Token), String> {
+  let name = |iter: &mut Cursor| {
+    iter
+      .next()
+      .filter(|name| name.kind == lexer::TokenKind::Name)
+      .ok_or(format!(
+        "{}: Expected a name after `#for`",
+        directive.location,
+      ))
+  };
+  let first = name(iter)?;
+  let header = match iter.next() {
+    Some(comma) if &*comma.value == "," => {
+      let second = name(iter)?;
+      iter.next().filter(|token| &*token.value == "in").map(|_| {
+        (Some(first), second)
+      })
+    }
+    Some(token) if &*token.value == "in" => Some((None, first)),
+    _ => None,
+  };
+  header.ok_or(format!(
+    "{}: Expected `in` after the names of `#for`",
+    directive.location,
+  ))
+}
+
+/// Parses the comma separated elements a `#for` loop runs
+/// over. Each is a single token or bracketed group, and
+/// parentheses group several tokens into one element.
+fn parse_for_elements(
+  iter: &mut Cursor,
+  directive: &lexer::Token,
+) -> Result<Vec<lexer::Tokens>, String> {
+  let mut elements = vec![];
+  while let Some(token) = iter.peek() {
+    if token.kind == lexer::TokenKind::EndFor {
+      break;
+    }
+    let start = iter.index;
+    let element = match &*token.value {
+      "(" => bracketed(iter),
+      "[" | "{" => bracketed(iter)
+        .map(|_| iter.tokens[start..iter.index].to_vec()),
+      _ => iter.next().map(|token| vec![token]),
+    }
+    .ok_or(format!(
+      "{}: Unclosed bracket in the elements of `#for`",
+      directive.location,
+    ))?;
+    elements.push(element);
+    if iter.next_if_value(",").is_none() {
+      break;
+    }
+  }
+  Ok(elements)
+}
+
+/// Takes the body of a block such as `#for` or `#scope`, up
+/// to the `end` directive that matches its `directive`. Only
+/// `nested` blocks may contain blocks of the same kind.
+fn get_block_body(
+  iter: &mut Cursor,
+  directive: &lexer::Token,
+  end: lexer::TokenKind,
+  nested: bool,
+) -> Result<lexer::Tokens, String> {
+  let mut body = vec![];
+  let mut depth = 0;
+  for token in iter.by_ref() {
+    if nested && token.kind == directive.kind {
+      depth += 1;
+    } else if token.kind == end && depth == 0 {
+      return Ok(body);
+    } else if token.kind == end {
+      depth -= 1;
+    }
+    body.push(token);
+  }
+  Err(format!(
+    "{}: `{}` without a matching `#end{}`",
+    directive.location,
+    directive.value,
+    &directive.value[1..],
+  ))
+}
+
+/// Reads the rest of a macro name qualified by its namespace,
+/// as in `std.class!` or `net::log!`, after its first name.
+/// Names joined by `.` could also index a table, so they are
+/// only read as one if they start with a declared namespace.
+/// A name after `::` or `goto` is a label, which `::` may
+/// close.
+fn qualified_name(
+  first: &lexer::Token,
+  iter: &mut Cursor,
+  macros: &Macros,
+) -> Result<Option<lexer::Token>, String> {
+  let label = iter
+    .index
+    .checked_sub(2)
+    .and_then(|index| iter.tokens.get(index))
+    .is_some_and(|token| {
+      &*token.value == "::" || token.is_keyword("goto")
+    });
+  if label {
+    return Ok(None);
+  }
+  let mut path = first.value.to_string();
+  let mut index = iter.index;
+  let mut colons = false;
+  loop {
+    let (Some(separator), Some(name)) =
+      (iter.tokens.get(index), iter.tokens.get(index + 1))
+    else {
+      return Ok(None);
+    };
+    match &*separator.value {
+      "." => {}
+      "::" => colons = true,
+      _ => return Ok(None),
+    }
+    if name.kind == lexer::TokenKind::Macro {
+      if !macros.is_namespace(&path) {
+        if colons {
+          return Err(format!(
+            "{}: Unknown namespace `{}`{}",
+            first.location,
+            path,
+            first.location.expansion_trace(),
+          ));
+        }
+        return Ok(None);
+      }
+      iter.index = index + 2;
+      return Ok(Some(lexer::Token {
+        kind: lexer::TokenKind::Macro,
+        value: format!("{}.{}", path, name.value).into(),
+        location: first.location.clone(),
+      }));
+    } else if name.kind != lexer::TokenKind::Name {
+      return Ok(None);
+    }
+    path = format!("{}.{}", path, name.value);
+    index += 2;
+  }
+}
+
+/// Replaces the name of a macro by the one it refers to. A
+/// name is looked up in the namespace of the macro whose body
+/// it comes from, or else in that of the `#namespace` block
+/// being expanded.
+fn resolve_name(
+  token: lexer::Token,
+  macros: &Macros,
+) -> Result<lexer::Token, String> {
+  let namespace = match &token.location.expansion {
+    Some(expansion) if !expansion.name.starts_with('#') => expansion
+      .name
+      .rsplit_once('.')
+      .map_or(String::new(), |(namespace, _)| namespace.to_string()),
+    _ => macros.namespace(),
+  };
+  match macros.resolve(&token.value, &namespace, token.location.file) {
+    Ok(name) => Ok(lexer::Token {
+      value: name.into(),
+      ..token
+    }),
+    Err(candidates) => Err(format!(
+      "{}: Ambiguous macro `{}`, which could be {}{}",
+      token.location,
+      token.value,
+      candidates
+        .iter()
+        .map(|candidate| format!("`{}`", candidate))
+        .collect::<Vec<_>>()
+        .join(" or "),
+      token.location.expansion_trace(),
+    )),
+  }
+}
+
+/// Reads the macro a directive such as `#undef` applies to.
+fn directive_name(
+  iter: &mut Cursor,
+  macros: &Macros,
+) -> Result<Option<lexer::Token>, String> {
+  let token = match iter.peek().cloned() {
+    Some(token) if token.kind == lexer::TokenKind::Macro => {
+      iter.next();
+      token
+    }
+    Some(token) if token.kind == lexer::TokenKind::Name => {
+      iter.next();
+      match qualified_name(&token, iter, macros)? {
+        Some(token) => token,
+        None => return Ok(None),
+      }
+    }
+    _ => return Ok(None),
+  };
+  resolve_name(token, macros).map(Some)
+}
+
+/// Reads the namespace named after `#namespace` or `#using`,
+/// whose parts may be joined by `.` or `::`.
+fn namespace_path(
+  iter: &mut Cursor,
+  directive: &lexer::Token,
+) -> Result<String, String> {
+  let error = || {
+    format!(
+      "{}: `{}` must be followed by a namespace name",
+      directive.location, directive.value,
+    )
+  };
+  let mut path = iter
+    .next()
+    .filter(|name| name.kind == lexer::TokenKind::Name)
+    .ok_or_else(error)?
+    .value
+    .to_string();
+  while iter
+    .peek()
+    .is_some_and(|token| matches!(&*token.value, "." | "::"))
+  {
+    iter.next();
+    let name = iter
+      .next()
+      .filter(|name| name.kind == lexer::TokenKind::Name)
+      .ok_or_else(error)?;
+    path = format!("{}.{}", path, name.value);
+  }
+  Ok(path)
+}
+
+/// Repeats `body` once per element, with `name` replaced by
+/// the element and `index`, if given, by its position.
+fn unroll(
+  index: Option<&lexer::Token>,
+  name: &lexer::Token,
+  elements: &[lexer::Tokens],
+  body: &lexer::Tokens,
+) -> lexer::Tokens {
+  let mut new_tokens = vec![];
+  for (i, element) in elements.iter().enumerate() {
+    let mut bindings = Bindings::new();
+    bindings.insert(&name.value, element.clone());
+    if let Some(index) = index {
+      bindings.insert(&index.value, vec![lexer::Token {
+        kind: lexer::TokenKind::Number,
+        value: (i + 1).to_string().into(),
+        location: index.location.clone(),
+      }]);
+    }
+    new_tokens.extend(substitute(body.clone(), &bindings));
+  }
+  new_tokens
+}
+
+/// Unrolls the `#for name in #...` loops of a variadic macro
+/// body over its varargs, before `#...` is replaced by them.
+fn unroll_vararg_loops(
+  body: lexer::Tokens,
+  varargs: &[lexer::Tokens],
+) -> Result<lexer::Tokens, String> {
+  let mut iter = Cursor::new(body);
+  let mut new_tokens = vec![];
+  while let Some(token) = iter.next() {
+    if token.kind != lexer::TokenKind::For {
+      new_tokens.push(token);
+      continue;
+    }
+    let start = iter.index;
+    let (index, name) = parse_for_header(&mut iter, &token)?;
+    if iter.peek().map(|list| &list.kind) != Some(&lexer::TokenKind::Vararg) {
+      iter.index = start;
+      new_tokens.push(token);
+      continue;
+    }
+    iter.next();
+    let body = get_block_body(
+      &mut iter,
+      &token,
+      lexer::TokenKind::EndFor,
+      true,
+    )?;
+    let body = unroll_vararg_loops(body, varargs)?;
+    new_tokens.extend(unroll(index.as_ref(), &name, varargs, &body));
+  }
+  Ok(new_tokens)
+}
+
+/// Expands the builtins a variadic macro body can inspect
+/// its varargs with: `__VA_COUNT__`, the vararg at an index
+/// with `__VA_ARG__(n)`, the varargs from an index on with
+/// `__VA_REST__(n)`, and `__VA_OPT__(...)`, which keeps its
+/// tokens only if there are varargs. The parameters in the
+/// rest of the body are substituted in the same pass, so
+/// that neither the arguments nor the varargs are scanned
+/// for builtins or parameters again.
+fn expand_vararg_builtins(
+  body: lexer::Tokens,
+  varargs: &[lexer::Tokens],
+  bindings: &Bindings,
+) -> Result<lexer::Tokens, String> {
+  let mut iter = Cursor::new(body);
+  let mut new_tokens = vec![];
+  while let Some(token) = iter.next() {
+    let name = &*token.value;
+    if token.kind != lexer::TokenKind::Name
+      || !matches!(
+        name,
+        "__VA_COUNT__" | "__VA_ARG__" | "__VA_REST__" | "__VA_OPT__"
+      )
+    {
+      new_tokens.extend(substitute(vec![token], bindings));
+      continue;
+    }
+    if name == "__VA_COUNT__" {
+      new_tokens.push(lexer::Token {
+        kind: lexer::TokenKind::Number,
+        value: varargs.len().to_string().into(),
+        location: token.location,
+      });
+      continue;
+    }
+    let inner = bracketed(&mut iter).ok_or(format!(
+      "{}: Expected parenthesized arguments after `{}`",
+      token.location, name,
+    ))?;
+    if name == "__VA_OPT__" {
+      if !varargs.is_empty() {
+        new_tokens.extend(expand_vararg_builtins(inner, varargs, bindings)?);
+      }
+      continue;
+    }
+    let index = match substitute(inner, bindings).as_slice() {
+      [number] => number.value.parse::<usize>().ok(),
+      _ => None,
+    }
+    .filter(|&index| index > 0)
+    .ok_or(format!(
+      "{}: `{}` expects a vararg index, starting at 1",
+      token.location, name,
+    ))?;
+    if name == "__VA_ARG__" {
+      let arg = varargs.get(index - 1).ok_or(format!(
+        "{}: `__VA_ARG__({})` is out of range, there are {} varargs{}",
+        token.location,
+        index,
+        varargs.len(),
+        token.location.expansion_trace(),
+      ))?;
+      new_tokens.extend(arg.iter().cloned());
+    } else {
+      let rest = varargs.get(index - 1..).unwrap_or_default();
+      new_tokens.extend(join_by_commas(rest.to_vec()));
+    }
+  }
+  Ok(new_tokens)
+}
+
+/// Records `invocation` as the origin of the tokens it
+/// expanded to, so later errors can point back at it.
+fn mark_expansion(
+  tokens: lexer::Tokens,
+  invocation: &lexer::Token,
+) -> lexer::Tokens {
+  let expansion = Rc::new(lexer::Expansion {
+    name: invocation.value.to_string(),
+    location: invocation.location.clone(),
+  });
+  tokens
+    .into_iter()
+    .map(|mut token| {
+      token.location.expansion = Some(expansion.clone());
+      token
+    })
+    .collect()
+}
+
+/// Picks the definition of the function macro `invocation`
+/// names that takes as many arguments as `args`.
+fn select_overload(
+  macros: &Macros,
+  invocation: &lexer::Token,
+  args: &[lexer::Tokens],
+) -> Result<Rc<FuncMacro>, String> {
+  // A trailing comma does not count as an argument.
+  let count = args
+    .iter()
+    .rposition(|arg| !arg.is_empty())
+    .map_or(0, |last| last + 1);
+  if let Some(func_macro) = macros.func(&invocation.value, count) {
+    return Ok(func_macro);
+  }
+  let overloads = macros.overloads(&invocation.value);
+  // With a single definition, a missing argument is reported
+  // by name when binding the arguments.
+  if let [only] = overloads.as_slice() {
+    if count < only.params.len() {
+      return Ok(only.clone());
+    }
+  }
+  let candidates = overloads
+    .iter()
+    .map(|overload| {
+      format!("\n  {} at {}", overload.signature(), overload.location)
+    })
+    .collect::<String>();
+  Err(format!(
+    "{}: No definition of `{}` takes {} arguments, candidates are:{}{}",
+    invocation.location,
+    invocation.value,
+    count,
+    candidates,
+    invocation.location.expansion_trace(),
+  ))
+}
+
+fn no_matching_rule(
+  rules_macro: &rules::RulesMacro,
+  invocation: &lexer::Token,
+  input: &[lexer::Token],
+) -> String {
+  let rules = rules_macro
+    .rules
+    .iter()
+    .map(|rule| format!("\n  {} at {}", rule.source, rule.location))
+    .collect::<String>();
+  format!(
+    "{}: No rule of `{}` matches `{}`, rules are:{}{}",
+    invocation.location,
+    invocation.value,
+    render_tokens(input.to_vec()),
+    rules,
+    invocation.location.expansion_trace(),
+  )
+}
+
+/// Checks that the arguments of `invocation` are separated
+/// as `func_macro` declares. A declared `,`, like the commas
+/// between varargs, accepts any of the delimiters.
+fn check_separators(
+  func_macro: &FuncMacro,
+  separators: &[lexer::Token],
+  invocation: &lexer::Token,
+) -> Result<(), String> {
+  for (i, found) in separators.iter().enumerate() {
+    let declared = func_macro.separators.get(i).map_or(",", String::as_str);
+    let matches = match declared {
+      "," => is_separator(found, &[]),
+      declared => *found.value == *declared,
+    };
+    if !matches {
+      return Err(format!(
+        "{}: Expected `{}` between the arguments of `{}`, found `{}`{}",
+        found.location,
+        declared,
+        invocation.value,
+        found.value,
+        invocation.location.expansion_trace(),
+      ));
+    }
+  }
+  Ok(())
+}
+
+/// Checks that expanding `invocation` does not re-enter a
+/// macro it was expanded from, and stays within the limits.
+fn check_expansion(
+  invocation: &lexer::Token,
+  macros: &mut Macros,
+) -> Result<(), String> {
+  let mut chain = vec![];
+  let mut expansion = invocation.location.expansion.as_ref();
+  while let Some(exp) = expansion {
+    chain.push(exp.name.as_str());
+    if exp.name == *invocation.value {
+      chain.reverse();
+      return Err(format!(
+        "{}: Recursive macro expansion: {} -> {}{}",
+        invocation.location,
+        chain.join(" -> "),
+        invocation.value,
+        invocation.location.expansion_trace(),
+      ));
+    }
+    expansion = exp.location.expansion.as_ref();
+  }
+  if !macros.count_expansion() {
+    return Err(format!(
+      "{}: More than {} macro expansions",
+      invocation.location, macros.limits.max_expansions,
+    ));
+  }
+  Ok(())
+}
+
+/// Expands the body `invocation` was replaced by. Bodies
+/// are counted as they nest, since arguments can nest
+/// expansions without leaving a trace in their tokens.
+fn expand_body(
+  body: lexer::Tokens,
+  invocation: &lexer::Token,
+  macros: &mut Macros,
+  options: &lua::Options,
+  new_tokens: &mut lexer::Tokens,
+) -> Result<(), String> {
+  if !macros.enter_expansion() {
+    return Err(format!(
+      "{}: Macro expansion nested more than {} levels deep{}",
+      invocation.location,
+      macros.limits.max_depth,
+      invocation.location.expansion_trace(),
+    ));
+  }
+  let result = expand_into(body, macros, options, new_tokens);
+  macros.leave_expansion();
+  result
+}
+
 fn process_tokens(
   tokens: lexer::Tokens,
-  value_macros: &mut Vec<ValueMacro>,
-  func_macros: &mut Vec<FuncMacro>,
+  macros: &mut Macros,
+  options: &lua::Options,
 ) -> Result<lexer::Tokens, String> {
   let mut new_tokens = vec![];
-  let mut iter = tokens.into_iter().peekable();
+  expand_into(tokens, macros, options, &mut new_tokens)?;
+  Ok(new_tokens)
+}
+
+/// Expands `tokens`, appending the result to `new_tokens` so
+/// that nested expansions write straight into the output.
+fn expand_into(
+  tokens: lexer::Tokens,
+  macros: &mut Macros,
+  options: &lua::Options,
+  new_tokens: &mut lexer::Tokens,
+) -> Result<(), String> {
+  let mut iter = Cursor::new(tokens);
+  let mut private = false;
 
   while let Some(token) = iter.next() {
-    if token.kind == lexer::TokenKind::Ifdef
-      || token.kind == lexer::TokenKind::Ifndef
-    {
-      let name = iter
-        .next()
-        .filter(|name| name.kind == lexer::TokenKind::Macro)
-        .ok_or(format!("{:?}: Expected macro name in `#ifdef`", token.location))?;
-      let in_values = value_macros
-        .into_iter()
-        .find(|val_macro| val_macro.name == name.value)
-        .is_some();
-      let in_funcs = func_macros
-        .into_iter()
-        .find(|func_macro| func_macro.name == name.value)
-        .is_some();
+    let token = match token.kind {
+      lexer::TokenKind::Name => {
+        match qualified_name(&token, &mut iter, macros)? {
+          Some(name) => resolve_name(name, macros)?,
+          None => token,
+        }
+      }
+      lexer::TokenKind::Macro => resolve_name(token, macros)?,
+      _ => token,
+    };
+    if is_conditional(&token.kind) {
+      let condition = if token.kind == lexer::TokenKind::If {
+        parse_condition(&mut iter, &token)?
+      } else {
+        let name = directive_name(&mut iter, macros)?.ok_or(format!(
+          "{}: Expected macro name in `#ifdef`",
+          token.location,
+        ))?;
+        vec![name]
+      };
       let mut body = vec![];
       let mut has_else = false;
       while let Some(next_token) = iter.next() {
-        if next_token.kind == lexer::TokenKind::Ifdef
-          || next_token.kind == lexer::TokenKind::Ifndef
-        {
+        if is_conditional(&next_token.kind) {
           body.push(next_token);
           skip_nested_ifdefs(&mut iter, &mut body);
           continue;
@@ -333,9 +1176,7 @@ fn process_tokens(
       let mut else_body = vec![];
       if has_else {
         while let Some(next_token) = iter.next() {
-          if next_token.kind == lexer::TokenKind::Ifdef
-            || next_token.kind == lexer::TokenKind::Ifndef
-          {
+          if is_conditional(&next_token.kind) {
             else_body.push(next_token);
             skip_nested_ifdefs(&mut iter, &mut else_body);
             continue;
@@ -345,115 +1186,212 @@ fn process_tokens(
           else_body.push(next_token);
         }
       }
-      let mut exists = in_values || in_funcs;
-      if token.kind == lexer::TokenKind::Ifndef {
-        exists = !exists
-      }
+      let exists = match token.kind {
+        lexer::TokenKind::If => {
+          let condition = process_tokens(condition, macros, options)?;
+          eval::truth(&condition, &token)?
+        }
+        lexer::TokenKind::Ifdef => macros.is_defined(&condition[0].value),
+        _ => !macros.is_defined(&condition[0].value),
+      };
       if exists {
-        let result =
-          process_tokens(body, value_macros, func_macros)?;
-        new_tokens.extend(result);
+        expand_into(body, macros, options, new_tokens)?;
       } else if has_else {
-        let result =
-          process_tokens(else_body, value_macros, func_macros)?;
-        new_tokens.extend(result);
+        expand_into(else_body, macros, options, new_tokens)?;
       }
     } else if token.kind == lexer::TokenKind::Endif {
       continue;
+    } else if token.kind == lexer::TokenKind::For {
+      let (index, name) = parse_for_header(&mut iter, &token)?;
+      let elements = parse_for_elements(&mut iter, &token)?;
+      let body = get_block_body(
+        &mut iter,
+        &token,
+        lexer::TokenKind::EndFor,
+        true,
+      )?;
+      let body = unroll(index.as_ref(), &name, &elements, &body);
+      expand_into(body, macros, options, new_tokens)?;
+    } else if token.kind == lexer::TokenKind::EndFor {
+      return Err(format!(
+        "{}: `#endfor` without a matching `#for`",
+        token.location,
+      ));
+    } else if token.kind == lexer::TokenKind::Lua {
+      let body = get_block_body(
+        &mut iter,
+        &token,
+        lexer::TokenKind::EndLua,
+        false,
+      )?;
+      let emitted = lua::run_block(&body, &token, macros, options)?;
+      expand_into(mark_expansion(emitted, &token), macros, options, new_tokens)?;
+    } else if token.kind == lexer::TokenKind::EndLua {
+      return Err(format!(
+        "{}: `#endlua` without a matching `#lua`",
+        token.location,
+      ));
     } else if token.kind == lexer::TokenKind::Define {
       let name = iter
         .next()
         .filter(|name| name.kind == lexer::TokenKind::Macro)
-        .ok_or(format!("{:?}: Expected macro name", token.location))?;
+        .ok_or(format!("{}: Expected macro name", token.location))?;
+      let name = lexer::Token {
+        value: macros.qualify(&name.value).into(),
+        ..name
+      };
+      let private = std::mem::take(&mut private);
+      macros.set_owner(&name.value, token.location.file, private);
       let eq_or_lparen = iter.next().ok_or(format!(
-        "{:?}: Expected '=' or '(' on macro declaration",
+        "{}: Expected '=' or '(' on macro declaration",
         name.location,
       ))?;
-      match eq_or_lparen.value.as_str() {
+      match &*eq_or_lparen.value {
         "=" => {
-          process_value_macro(&mut iter, value_macros, name.clone())
-            .ok_or(format!("{:?}: Failed parsing value macro", name.clone().location))?
+          process_value_macro(&mut iter, macros, name.clone())
+            .ok_or(format!("{}: Failed parsing value macro", name.clone().location))?
         }
-        "(" | "[" | "{" => process_func_macro(&mut iter, func_macros, name.clone())
-          .ok_or(format!("{:?}: Failed to parse func macro", name.clone().location))?,
-        "#end" => value_macros.push(ValueMacro {
-          name: name.value,
+        "(" | "[" | "{" => process_func_macro(&mut iter, macros, name.clone())
+          .ok_or(format!("{}: Failed to parse func macro", name.clone().location))?,
+        "#end" => macros.define_value(ValueMacro {
+          name: name.value.to_string(),
           tokens: vec![],
         }),
         _ => {
-          return Err(format!("{:?}: Expected '=', '(', or '#end'", name.clone().location))
+          return Err(format!("{}: Expected '=', '(', or '#end'", name.clone().location))
+        }
+      }
+    } else if token.kind == lexer::TokenKind::Rules {
+      let name = iter
+        .next()
+        .filter(|name| name.kind == lexer::TokenKind::Macro)
+        .ok_or(format!("{}: Expected macro name", token.location))?;
+      let name = lexer::Token {
+        value: macros.qualify(&name.value).into(),
+        ..name
+      };
+      let private = std::mem::take(&mut private);
+      macros.set_owner(&name.value, token.location.file, private);
+      let body = get_macro_body(&mut iter);
+      macros.define_rules(rules::parse_rules(&name, &body)?);
+    } else if token.kind == lexer::TokenKind::Private {
+      if !iter.peek().is_some_and(|next| {
+        matches!(next.kind, lexer::TokenKind::Define | lexer::TokenKind::Rules)
+      }) {
+        return Err(format!(
+          "{}: `#private` must be followed by `#define` or `#rules`",
+          token.location,
+        ));
+      }
+      private = true;
+    } else if token.kind == lexer::TokenKind::Export {
+      loop {
+        let name = iter
+          .next()
+          .filter(|name| name.kind == lexer::TokenKind::Macro)
+          .ok_or(format!(
+            "{}: `#export` must be followed by macro names",
+            token.location,
+          ))?;
+        macros.export(token.location.file, macros.qualify(&name.value));
+        if iter.next_if_value(",").is_none() {
+          break;
         }
       }
+    } else if token.kind == lexer::TokenKind::Macro
+      && &*token.value == "eval!"
+      && !macros.is_defined(&token.value)
+    {
+      let input = bracketed(&mut iter).ok_or(format!(
+        "{}: Expected a bracketed expression after `eval!`",
+        token.location,
+      ))?;
+      let input = process_tokens(input, macros, options)?;
+      new_tokens.extend(eval::eval(&input, &token)?);
+    } else if token.kind == lexer::TokenKind::Macro
+      && INSPECTORS.contains(&&*token.value)
+      && !macros.is_defined(&token.value)
+    {
+      let input = bracketed(&mut iter).ok_or(format!(
+        "{}: Expected bracketed tokens after `{}`",
+        token.location, token.value,
+      ))?;
+      new_tokens.push(inspect_tokens(&token, &input));
     } else if token.kind == lexer::TokenKind::Macro {
-      let value_macro_opt = value_macros
-        .clone()
-        .into_iter()
-        .find(|val_macro| val_macro.name == token.value);
-      if let Some(value_macro) = value_macro_opt {
-        let tokens = value_macro.tokens;
-        let tokens = apply_bang_pastes(tokens);
-        let result = process_tokens(
-          tokens,
-          value_macros,
-          func_macros,
-        )?;
-        new_tokens.extend(result);
+      if let Some(owner) =
+        macros.private_owner(&token.value, token.location.file)
+      {
+        return Err(format!(
+          "{}: `{}` is private to {}{}",
+          token.location,
+          token.value,
+          owner.name(),
+          token.location.expansion_trace(),
+        ));
+      }
+      if macros.is_defined(&token.value) {
+        check_expansion(&token, macros)?;
+      }
+      if let Some(value_macro) = macros.value(&token.value) {
+        let tokens =
+          hygiene::rename_introduced(value_macro.tokens.clone(), &[]);
+        let tokens =
+          mark_expansion(apply_bang_pastes(tokens), &token);
+        expand_body(tokens, &token, macros, options, new_tokens)?;
         continue;
       }
-      let func_macro_opt = func_macros
-        .clone()
-        .into_iter()
-        .find(|func_macro| func_macro.name == token.value);
-      if let Some(func_macro) = func_macro_opt {
-        let args = parse_func_args(&mut iter).ok_or(format!(
-          "{:?}: Failed parsing arguments on macro invocation",
-          token.location,
-        ))?;
-        let params = func_macro
-          .params
-          .into_iter()
-          .map(|s| lexer::Token {
-            kind: lexer::TokenKind::Name,
-            value: s,
-            location: token.clone().location,
-          })
-          .collect::<Vec<_>>();
-        let mut body = func_macro.tokens;
-        let mut rest = args.clone();
-        for (arg, param) in args.into_iter().zip(&params) {
-          body = replace_tokens(body, param.clone(), arg);
-          rest.remove(0);
-        }
-        let rest = rest
-          .into_iter()
-          .filter(|toks| !toks.is_empty())
+      let overloads = macros.overloads(&token.value);
+      if !overloads.is_empty() {
+        let declared = overloads
+          .iter()
+          .flat_map(|overload| overload.separators.iter().cloned())
           .collect::<Vec<_>>();
+        let (args, separators) = parse_func_args(&mut iter, &declared)
+          .ok_or(format!(
+            "{}: Failed parsing arguments on macro invocation",
+            token.location,
+          ))?;
+        let func_macro = select_overload(macros, &token, &args)?;
+        check_separators(&func_macro, &separators, &token)?;
+        let (bindings, rest) = bind_args(&func_macro, args, &token)?;
+        let body = hygiene::rename_introduced(
+          func_macro.tokens.clone(),
+          &func_macro.param_names(),
+        );
+        let body = mark_expansion(body, &token);
+        let body = if func_macro.vararg {
+          expand_vararg_builtins(body, &rest, &bindings)?
+        } else {
+          substitute(body, &bindings)
+        };
         let mut body = apply_bang_pastes(body);
         let stringified = rest
-          .clone()
-          .into_iter()
+          .iter()
           .flat_map(|arg| vec![
             lexer::Token {
               kind: lexer::TokenKind::String,
               value: format!(
                 "{:?}",
-                render_tokens(arg),
-              ),
+                render_tokens(arg.clone()),
+              )
+              .into(),
               location: token.clone().location,
             },
             lexer::Token {
               kind: lexer::TokenKind::Delimiter,
-              value: ",".to_string(),
+              value: ",".into(),
               location: token.clone().location
             }
           ]).collect::<Vec<_>>();
+        let varargs = rest.clone();
         let rest = join_by_commas(rest);
         if func_macro.vararg {
+          body = unroll_vararg_loops(body, &varargs)?;
           body = replace_tokens(
             body,
             lexer::Token {
               kind: lexer::TokenKind::Vararg,
-              value: "#...".to_string(),
+              value: "#...".into(),
               location: token.clone().location,
             },
             rest,
@@ -462,68 +1400,133 @@ fn process_tokens(
             body,
             lexer::Token {
               kind: lexer::TokenKind::StringifyVararg,
-              value: "#...#".to_string(),
+              value: "#...#".into(),
               location: token.clone().location,
             },
             stringified,
           );
         }
-        let result =
-          process_tokens(body, value_macros, func_macros)?;
-        new_tokens.extend(result);
+        expand_body(body, &token, macros, options, new_tokens)?;
+        continue;
+      }
+      if let Some(rules_macro) = macros.rules(&token.value) {
+        let input = bracketed(&mut iter).ok_or(format!(
+          "{}: Expected a bracketed invocation of `{}`",
+          token.location, token.value,
+        ))?;
+        let (rule, captures) = rules::select_rule(&rules_macro, &input)
+          .ok_or_else(|| no_matching_rule(&rules_macro, &token, &input))?;
+        let body = hygiene::rename_introduced(
+          rule.body.clone(),
+          &rule.capture_names(),
+        );
+        let body = mark_expansion(body, &token);
+        let mut transcribed = vec![];
+        rules::transcribe(&body, &captures, &mut transcribed)?;
+        let body = apply_bang_pastes(transcribed);
+        expand_body(body, &token, macros, options, new_tokens)?;
         continue;
       }
       return Err(
-        format!("{:?}: Attempting to call non-existent macro: `{}`", token.location, token.value),
+        format!("{}: Attempting to call non-existent macro: `{}`", token.location, token.value),
       );
     } else if token.kind == lexer::TokenKind::Undef {
-      let name = iter
-        .next()
-        .filter(|tok| tok.kind == lexer::TokenKind::Macro)
-        .ok_or(format!("{:?}: `#undef` must be followed by a macro name", token.location))?;
-      value_macros
-        .retain(|val_macro| val_macro.name != name.value);
-      func_macros
-        .retain(|func_macro| func_macro.name != name.value);
+      let name = directive_name(&mut iter, macros)?
+        .ok_or(format!("{}: `#undef` must be followed by a macro name", token.location))?;
+      macros.undefine(&name.value);
+    } else if token.kind == lexer::TokenKind::PushMacro
+      || token.kind == lexer::TokenKind::PopMacro
+    {
+      let name = directive_name(&mut iter, macros)?.ok_or(format!(
+        "{}: `{}` must be followed by a macro name",
+        token.location, token.value,
+      ))?;
+      if token.kind == lexer::TokenKind::PushMacro {
+        macros.push_macro(&name.value);
+      } else if !macros.pop_macro(&name.value) {
+        return Err(format!(
+          "{}: `#pop_macro {}` without a matching `#push_macro`",
+          token.location, name.value,
+        ));
+      }
+    } else if token.kind == lexer::TokenKind::Scope {
+      let body = get_block_body(
+        &mut iter,
+        &token,
+        lexer::TokenKind::EndScope,
+        true,
+      )?;
+      let definitions = macros.definitions();
+      expand_into(body, macros, options, new_tokens)?;
+      macros.restore(definitions);
+    } else if token.kind == lexer::TokenKind::Namespace {
+      let path = namespace_path(&mut iter, &token)?;
+      let body = get_block_body(
+        &mut iter,
+        &token,
+        lexer::TokenKind::EndNamespace,
+        true,
+      )?;
+      macros.enter_namespace(&path);
+      let result = expand_into(body, macros, options, new_tokens);
+      macros.leave_namespace();
+      result?;
+    } else if token.kind == lexer::TokenKind::Using {
+      let path = namespace_path(&mut iter, &token)?;
+      if !macros.is_namespace(&path) {
+        return Err(format!(
+          "{}: Unknown namespace `{}`",
+          token.location, path,
+        ));
+      }
+      macros.use_namespace(token.location.file, path);
+    } else if token.kind == lexer::TokenKind::EndScope
+      || token.kind == lexer::TokenKind::EndNamespace
+    {
+      return Err(format!(
+        "{}: `{}` without a matching `#{}`",
+        token.location,
+        token.value,
+        &token.value["#end".len()..],
+      ));
     } else if token.kind == lexer::TokenKind::Include {
       let path = iter
         .next()
         .filter(|tok| tok.kind == lexer::TokenKind::String)
-        .ok_or(format!("{:?}: Include must be followed by a string literal", token.location))?;
+        .ok_or(format!("{}: Include must be followed by a string literal", token.location))?;
       let result = process_file(
-        (&path.value[1..path.value.len() - 1]).to_string(),
+        path.value[1..path.value.len() - 1].to_string(),
       )?;
-      let processed =
-        process_tokens(result, value_macros, func_macros)?;
-      new_tokens.extend(processed);
+      expand_into(result, macros, options, new_tokens)?;
     } else if token.kind == lexer::TokenKind::Line {
       new_tokens.push(lexer::Token {
         kind: lexer::TokenKind::Number,
-        value: format!("{}", token.location.line),
+        value: token.location.line.to_string().into(),
         location: token.location,
       })
     } else {
-      new_tokens.push(token.clone());
+      new_tokens.push(token);
     }
   }
 
-  Ok(new_tokens)
+  Ok(())
+}
+
+/// Whether `token` is a double quoted string, the only kind
+/// that adjacent literals are concatenated for.
+fn is_quoted_string(token: &lexer::Token) -> bool {
+  token.kind == lexer::TokenKind::String && token.value.starts_with('"')
 }
 
 fn concat_string_lits_rest(
   iter: &mut Peekable<IntoIter<lexer::Token>>,
   parts: &mut Vec<String>,
 ) {
-  while let Some(next_token) = iter.clone().peek() {
-    if next_token.kind == lexer::TokenKind::String {
-      iter.next();
-      parts.push(
-        (&next_token.value[1..next_token.value.len() - 1])
-          .to_string(),
-      );
-      continue;
-    }
-    break;
+  while let Some(next_token) = iter.next_if(is_quoted_string) {
+    parts.push(
+      next_token.value[1..next_token.value.len() - 1]
+        .to_string(),
+    );
   }
 }
 
@@ -531,15 +1534,15 @@ fn concat_string_lits(tokens: lexer::Tokens) -> lexer::Tokens {
   let mut iter = tokens.into_iter().peekable();
   let mut new_tokens = vec![];
   while let Some(token) = iter.next() {
-    if token.kind == lexer::TokenKind::String {
-      let mut parts = vec![(&token.value
-        [1..token.value.len() - 1])
+    if is_quoted_string(&token) {
+      let mut parts = vec![token.value
+        [1..token.value.len() - 1]
         .to_string()];
       concat_string_lits_rest(&mut iter, &mut parts);
       let string = parts.join("");
       new_tokens.push(lexer::Token {
         kind: lexer::TokenKind::String,
-        value: ("\"".to_owned() + &string + "\"").to_string(),
+        value: format!("\"{}\"", string).into(),
         location: token.clone().location,
       })
     } else {
@@ -558,7 +1561,7 @@ fn apply_pastes_rest(
       iter.next();
       if let Some(name_token) = iter.next() {
         if name_token.kind == lexer::TokenKind::Name {
-          parts.push(name_token.value);
+          parts.push(name_token.value.to_string());
           continue;
         }
       }
@@ -572,12 +1575,12 @@ fn apply_pastes(tokens: lexer::Tokens) -> lexer::Tokens {
   let mut new_tokens = vec![];
   while let Some(token) = iter.next() {
     if token.kind == lexer::TokenKind::Name {
-      let mut parts = vec![token.clone().value];
+      let mut parts = vec![token.value.to_string()];
       apply_pastes_rest(&mut iter, &mut parts);
       let string = parts.join("");
       new_tokens.push(lexer::Token {
         kind: lexer::TokenKind::Name,
-        value: string,
+        value: string.into(),
         location: token.clone().location,
       });
     } else {
@@ -600,41 +1603,40 @@ fn add_header_guard(
   tokens: Vec<lexer::Token>,
 ) -> Vec<lexer::Token> {
   let location = lexer::Location {
-    file: path.clone(),
-    line: 0,
-    column: 0,
+    file: files::intern(&path),
+    ..lexer::Location::default()
   };
   let mut new_tokens = vec![
     lexer::Token {
       kind: lexer::TokenKind::Ifndef,
-      value: "#ifndef".to_string(),
+      value: "#ifndef".into(),
       location: location.clone(),
     },
     lexer::Token {
       kind: lexer::TokenKind::Macro,
-      value: path.clone(),
+      value: path.as_str().into(),
       location: location.clone(),
     },
     lexer::Token {
       kind: lexer::TokenKind::Define,
-      value: "#define".to_string(),
+      value: "#define".into(),
       location: location.clone(),
     },
     lexer::Token {
       kind: lexer::TokenKind::Macro,
-      value: path,
+      value: path.into(),
       location: location.clone(),
     },
     lexer::Token {
       kind: lexer::TokenKind::EndDefine,
-      value: "#end".to_string(),
+      value: "#end".into(),
       location: location.clone(),
     },
   ];
   new_tokens.extend(tokens);
   new_tokens.push(lexer::Token {
     kind: lexer::TokenKind::Endif,
-    value: "#endif".to_string(),
+    value: "#endif".into(),
     location,
   });
   new_tokens
@@ -644,26 +1646,22 @@ fn add_flags(
   flags: Vec<String>,
   tokens: lexer::Tokens,
 ) -> lexer::Tokens {
-  let location = lexer::Location {
-    file: "".to_string(),
-    line: 0,
-    column: 0,
-  };
+  let location = lexer::Location::default();
   let flags = flags.into_iter().flat_map(|flag| {
     vec![
       lexer::Token {
         kind: lexer::TokenKind::Define,
-        value: "#define".to_string(),
+        value: "#define".into(),
         location: location.clone()
       },
       lexer::Token {
         kind: lexer::TokenKind::Macro,
-        value: (flag + "!").to_string(),
+        value: format!("{}!", flag).into(),
         location: location.clone()
       },
       lexer::Token {
         kind: lexer::TokenKind::EndDefine,
-        value: "#end".to_string(),
+        value: "#end".into(),
         location: location.clone()
       },
     ]
@@ -678,10 +1676,7 @@ fn process_file(path: String) -> Result<lexer::Tokens, String> {
   input_file
     .read_to_string(&mut input)
     .map_err(|e| format!("{}", e))?;
-  println!("A");
-  let tokens =
-    lexer::lex(&path, input.clone()).ok_or("Tokenization failed")?;
-  println!("B");
+  let tokens = lexer::lex(&path, &input).map_err(lexer::join_errors)?;
   let tokens = add_header_guard(path, tokens);
   Ok(tokens)
 }
@@ -692,20 +1687,13 @@ fn strip_trailing_commas(
   let mut new_tokens = vec![];
   let mut iter = tokens.into_iter().peekable();
   while let Some(token) = iter.next() {
-    if token.value.as_str() == "," {
-      if let Some(next_token) = iter.clone().peek() {
-        if next_token.value.as_str() == ")"
-          || next_token.value.as_str() == "]"
-          || next_token.value.as_str() == "}"
-        {
-          iter.next();
-          new_tokens.push(next_token.clone());
-        } else {
-          new_tokens.push(token);
-        }
-      } else {
-        new_tokens.push(token);
-      }
+    if &*token.value == "," {
+      let closing = iter.next_if(|next_token| {
+        &*next_token.value == ")"
+          || &*next_token.value == "]"
+          || &*next_token.value == "}"
+      });
+      new_tokens.push(closing.unwrap_or(token));
     } else {
       new_tokens.push(token);
     }
@@ -716,12 +1704,19 @@ fn strip_trailing_commas(
 enum CliMode {
   Com,
   Run,
+  Check,
 }
 
 struct CliOptions {
   input_path: String,
   output_path: String,
   flags: Vec<String>,
+  format: bool,
+  indent_width: usize,
+  minify: bool,
+  rename_locals: bool,
+  limits: Limits,
+  lua: Option<String>,
   mode: CliMode,
 }
 
@@ -731,36 +1726,75 @@ fn print_usage() {
   );
   println!("    <mode>      run. Runs the file");
   println!("                com. Compiles the file");
-  println!("");
+  println!(
+    "                check. Checks the expanded file for Lua syntax errors"
+  );
+  println!();
   println!("    <file>      Path to the file");
-  println!("");
+  println!();
   println!("    --flags     Comma separated list of flags");
   println!(
     "                that are treated as empty definitions"
   );
+  println!("    --format    Pretty prints the output");
+  println!("    --indent    Indent width used by --format (default: 2)");
+  println!("    --minify    Strips all unnecessary whitespace");
+  println!(
+    "    --rename-locals  Shortens local names when minifying"
+  );
+  println!(
+    "    --max-depth       Deepest allowed macro nesting (default: 256)"
+  );
+  println!(
+    "    --max-expansions  Most macro expansions allowed (default: 1000000)"
+  );
+  println!(
+    "    --lua       Lua interpreter for `run` and `#lua` (default: {})",
+    lua::DEFAULT_INTERPRETER,
+  );
   exit(1);
 }
 
+/// Parses the number following `prefix` in `arg`, exiting
+/// with the usage if it is not one.
+fn parse_count(arg: &str, prefix: &str) -> usize {
+  match arg[prefix.len()..].parse() {
+    Ok(count) => count,
+    Err(_) => {
+      println!("Error: Invalid number: {}", arg);
+      print_usage();
+      exit(1);
+    }
+  }
+}
+
 fn process_cli_args(args: &mut Vec<String>) -> CliOptions {
-  if args.len() <= 0 {
+  if args.is_empty() {
     println!("Error: expected mode");
     print_usage();
   }
   let mut input_path = "".to_string();
   let mut output_path = "out.lua".to_string();
   let mut flags = vec![];
+  let mut format = false;
+  let mut indent_width = 2;
+  let mut minify = false;
+  let mut rename_locals = false;
+  let mut limits = Limits::default();
+  let mut lua = None;
   let mode = match args.remove(0).as_str() {
     "com" => CliMode::Com,
     "run" => CliMode::Run,
+    "check" => CliMode::Check,
     mode => {
       println!("Error: Invalid mode: {}", mode);
       print_usage();
       exit(1);
     }
   };
-  while args.len() > 0 {
+  while !args.is_empty() {
     if args[0].starts_with("--flags=") {
-      flags = (&args[0]["--flags=".len()..])
+      flags = args[0]["--flags=".len()..]
         .split(',')
         .map(|flag| flag.to_string())
         .collect();
@@ -769,13 +1803,28 @@ fn process_cli_args(args: &mut Vec<String>) -> CliOptions {
       args.remove(0);
       output_path = args.remove(0);
     } else if args[0].as_str() == "--format" {
+      format = true;
       args.remove(0);
-    } else {
-      input_path = (&args[0].clone()).to_string();
+    } else if args[0].as_str() == "--minify" {
+      minify = true;
+      args.remove(0);
+    } else if args[0].as_str() == "--rename-locals" {
+      rename_locals = true;
       args.remove(0);
+    } else if args[0].starts_with("--indent=") {
+      indent_width = parse_count(&args.remove(0), "--indent=");
+    } else if args[0].starts_with("--max-depth=") {
+      limits.max_depth = parse_count(&args.remove(0), "--max-depth=");
+    } else if args[0].starts_with("--max-expansions=") {
+      limits.max_expansions =
+        parse_count(&args.remove(0), "--max-expansions=");
+    } else if args[0].starts_with("--lua=") {
+      lua = Some(args.remove(0)["--lua=".len()..].to_string());
+    } else {
+      input_path = args.remove(0);
     }
   }
-  if input_path.len() == 0 {
+  if input_path.is_empty() {
     println!("Error: Expected input file path");
     print_usage();
   }
@@ -783,43 +1832,75 @@ fn process_cli_args(args: &mut Vec<String>) -> CliOptions {
     input_path,
     output_path,
     flags,
+    format,
+    indent_width,
+    minify,
+    rename_locals,
+    limits,
+    lua,
     mode,
   }
 }
 
+fn preprocess(opts: &CliOptions) -> Result<lexer::Tokens, String> {
+  let processed = process_file(opts.input_path.to_string())?;
+  let processed = add_flags(opts.flags.clone(), processed);
+  let mut macros = Macros::default();
+  macros.limits = opts.limits;
+  let options = lua::Options {
+    flags: opts.flags.clone(),
+    interpreter: opts.lua.clone(),
+  };
+  let processed = process_tokens(processed, &mut macros, &options)?;
+  let processed = apply_pastes(processed);
+  let processed = concat_string_lits(processed);
+  Ok(strip_trailing_commas(processed))
+}
+
 fn main() {
   let mut args: Vec<String> = env::args().collect();
   args.remove(0);
   let opts = process_cli_args(&mut args);
-  let input_path = opts.input_path;
-  let output_path = opts.output_path;
-  let flags = opts.flags;
-  let processed = match process_file(input_path.to_string()) {
+  let processed = match preprocess(&opts) {
     Err(e) => {
       eprintln!("{}", e);
-      return;
+      exit(1);
     }
     Ok(p) => p,
   };
-  let processed = add_flags(flags, processed);
-  let processed =
-    process_tokens(processed, &mut vec![], &mut vec![])
-      .expect("Processing failed");
-  let processed = apply_pastes(processed);
-  let processed = concat_string_lits(processed);
-  let processed = strip_trailing_commas(processed);
-  let string = render_tokens(processed);
+  if let CliMode::Com | CliMode::Check = opts.mode {
+    if let Err(e) = parser::check(&processed) {
+      eprintln!("{}", e);
+      exit(1);
+    }
+  }
+  if let CliMode::Check = opts.mode {
+    return;
+  }
+  let output_path = opts.output_path;
+  let string = if opts.minify {
+    let processed = if opts.rename_locals {
+      minify::rename_locals(&processed)
+    } else {
+      processed
+    };
+    minify::render_minified(&processed)
+  } else if opts.format {
+    format::format_tokens(&processed, opts.indent_width)
+  } else {
+    render_tokens(processed)
+  };
   let mut output_file = File::create(output_path.clone())
     .expect("Could not create file");
-  match output_file.write_all(string.as_bytes()) {
-    Err(e) => eprintln!("Error: {}", e),
-    Ok(_) => {}
-  };
-  let _ = Command::new("stylua").arg(output_path.clone()).output();
+  if let Err(e) = output_file.write_all(string.as_bytes()) {
+    eprintln!("Error: {}", e);
+  }
   match opts.mode {
-    CliMode::Com => {}
+    CliMode::Com | CliMode::Check => {}
     CliMode::Run => {
-      let _ = Command::new("lua5.4")
+      let interpreter =
+        opts.lua.as_deref().unwrap_or(lua::DEFAULT_INTERPRETER);
+      let _ = Command::new(interpreter)
         .arg(output_path.clone())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
@@ -0,0 +1,352 @@
+use crate::format::starts_statement;
+use crate::lexer::{Token, TokenKind, Tokens, KEYWORDS};
+use std::collections::HashSet;
+
+const GLUED_PAIRS: [&str; 12] = [
+  "--", "==", "<=", ">=", "~=", "//", "<<", ">>", "::", "..",
+  "[[", "[=",
+];
+
+fn is_word_char(c: char) -> bool {
+  c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Whether writing `token` right after `prev` would make
+/// the Lua lexer read them differently.
+fn needs_separator(prev: &Token, token: &Token) -> bool {
+  let (Some(last), Some(first)) =
+    (prev.value.chars().last(), token.value.chars().next())
+  else {
+    return false;
+  };
+  if is_word_char(last) && is_word_char(first) {
+    return true;
+  }
+  if prev.kind == TokenKind::Number && first == '.' {
+    return true;
+  }
+  GLUED_PAIRS.contains(&format!("{}{}", last, first).as_str())
+}
+
+/// Renders a token stream with the minimum amount of
+/// whitespace needed to keep the tokens apart.
+pub fn render_minified(tokens: &Tokens) -> String {
+  let mut output = String::new();
+  let mut prev: Option<&Token> = None;
+  for token in tokens {
+    if prev.is_some_and(|prev| needs_separator(prev, token)) {
+      output.push(' ');
+    }
+    output.push_str(&token.value);
+    prev = Some(token);
+  }
+  output.push('\n');
+  output
+}
+
+fn short_names(
+  reserved: &HashSet<String>,
+) -> impl Iterator<Item = String> + '_ {
+  let first: Vec<char> =
+    ('a'..='z').chain('A'..='Z').chain(['_']).collect();
+  let rest: Vec<char> =
+    first.iter().copied().chain('0'..='9').collect();
+  (0usize..)
+    .map(move |n| {
+      let mut name = first[n % first.len()].to_string();
+      let mut n = n / first.len();
+      while n > 0 {
+        n -= 1;
+        name.push(rest[n % rest.len()]);
+        n /= rest.len();
+      }
+      name
+    })
+    .filter(|name| !reserved.contains(name))
+}
+
+/// Finds the comma separated names starting at `index`,
+/// skipping `<const>`/`<close>` attributes, and returns their
+/// indices along with the index after the list.
+pub fn name_list(tokens: &Tokens, mut index: usize) -> (Vec<usize>, usize) {
+  let mut names = vec![];
+  while tokens.get(index).is_some_and(|t| t.kind == TokenKind::Name) {
+    names.push(index);
+    index += 1;
+    if tokens.get(index).is_some_and(|t| &*t.value == "<") {
+      index += 3;
+    }
+    if tokens.get(index).is_none_or(|t| &*t.value != ",") {
+      break;
+    }
+    index += 1;
+  }
+  (names, index)
+}
+
+/// Whether the name at `index` is a field access or a table
+/// key rather than a variable. `in_table` tells whether its
+/// innermost bracket is a `{`.
+pub fn is_field(tokens: &Tokens, index: usize, in_table: bool) -> bool {
+  let prev = index.checked_sub(1).map(|i| &*tokens[i].value);
+  if matches!(prev, Some("." | ":" | "::" | "goto")) {
+    return true;
+  }
+  in_table
+    && matches!(prev, Some("{" | "," | ";"))
+    && tokens.get(index + 1).is_some_and(|next| &*next.value == "=")
+}
+
+struct Pending {
+  scope_count: usize,
+  depth: usize,
+  bindings: Vec<(String, String)>,
+}
+
+struct Renamer<'a> {
+  tokens: &'a Tokens,
+  names: Vec<String>,
+  scopes: Vec<Vec<(String, String)>>,
+  renames: Vec<Option<String>>,
+  pending: Vec<Pending>,
+  loop_vars: Vec<Pending>,
+  pending_pops: Vec<(usize, usize)>,
+  brackets: Vec<&'a str>,
+}
+
+impl<'a> Renamer<'a> {
+  fn visible(&self) -> usize {
+    self.scopes.iter().map(|scope| scope.len()).sum()
+  }
+
+  fn fresh(&self, offset: usize) -> String {
+    self.names[self.visible() + offset].clone()
+  }
+
+  fn lookup(&self, name: &str) -> Option<String> {
+    self
+      .scopes
+      .iter()
+      .rev()
+      .flat_map(|scope| scope.iter().rev())
+      .find(|(old, _)| old == name)
+      .map(|(_, new)| new.clone())
+  }
+
+  fn value(&self, index: usize) -> Option<&'a str> {
+    self.tokens.get(index).map(|token| &*token.value)
+  }
+
+  fn is_name(&self, index: usize) -> bool {
+    self.tokens.get(index).is_some_and(|token| {
+      token.kind == TokenKind::Name
+    })
+  }
+
+  fn bind(&mut self, bindings: Vec<(String, String)>) {
+    if let Some(scope) = self.scopes.last_mut() {
+      scope.extend(bindings);
+    }
+  }
+
+  fn push_scope(&mut self) {
+    self.scopes.push(vec![]);
+  }
+
+  fn pop_scope(&mut self) {
+    if self.scopes.len() > 1 {
+      self.scopes.pop();
+    }
+  }
+
+  /// Activates `local` declarations and closes `repeat`
+  /// scopes whose statement has just ended.
+  fn settle(&mut self, statement_ended: bool) {
+    let scope_count = self.scopes.len();
+    let depth = self.brackets.len();
+    let here = |count: usize, at: usize| {
+      statement_ended && count == scope_count && at == depth
+    };
+    if let Some(index) =
+      self.pending.iter().position(|p| here(p.scope_count, p.depth))
+    {
+      let pending = self.pending.remove(index);
+      self.bind(pending.bindings);
+    }
+    if let Some(&(count, at)) = self.pending_pops.last() {
+      if here(count, at) {
+        self.pending_pops.pop();
+        self.pop_scope();
+      }
+    }
+  }
+
+  /// Declares the names listed at `index`, returning the new
+  /// bindings along with the index after the list.
+  fn declare_list(
+    &mut self,
+    index: usize,
+  ) -> (Vec<(String, String)>, usize) {
+    let (indices, next) = name_list(self.tokens, index);
+    let mut bindings = vec![];
+    for index in indices {
+      let old = self.tokens[index].value.to_string();
+      let new = self.fresh(bindings.len());
+      self.renames[index] = Some(new.clone());
+      bindings.push((old, new));
+    }
+    (bindings, next)
+  }
+
+  fn is_field(&self, index: usize) -> bool {
+    is_field(self.tokens, index, self.brackets.last() == Some(&"{"))
+  }
+
+  fn run(&mut self) {
+    let mut index = 0;
+    let mut skip_until = 0;
+    while index < self.tokens.len() {
+      let token = &self.tokens[index];
+      let value = &*token.value;
+      let prev = index.checked_sub(1).map(|i| &self.tokens[i]);
+      let statement_ended = prev.is_some_and(|prev| {
+        starts_statement(self.tokens, index) || &*prev.value == ";"
+      }) || (token.kind == TokenKind::Keyword
+        && matches!(value, "end" | "else" | "elseif" | "until"));
+      self.settle(statement_ended);
+      if index < skip_until {
+        index += 1;
+        continue;
+      }
+      match value {
+        "(" | "[" | "{" => self.brackets.push(value),
+        ")" | "]" | "}" => {
+          self.brackets.pop();
+        }
+        _ => {}
+      }
+      if token.kind != TokenKind::Name
+        && token.kind != TokenKind::Keyword
+      {
+        index += 1;
+        continue;
+      }
+      match value {
+        "do" | "then" | "repeat" => {
+          let scope_count = self.scopes.len();
+          let depth = self.brackets.len();
+          self.push_scope();
+          if let Some(vars) = self.loop_vars.pop_if(|vars| {
+            vars.scope_count == scope_count && vars.depth == depth
+          }) {
+            self.bind(vars.bindings);
+          }
+        }
+        "else" => {
+          self.pop_scope();
+          self.push_scope();
+        }
+        "elseif" | "end" => self.pop_scope(),
+        "until" => self
+          .pending_pops
+          .push((self.scopes.len(), self.brackets.len())),
+        "for" => {
+          let (bindings, next) = self.declare_list(index + 1);
+          // Loop variables only become visible inside the body.
+          self.loop_vars.push(Pending {
+            scope_count: self.scopes.len(),
+            depth: self.brackets.len(),
+            bindings,
+          });
+          skip_until = next;
+        }
+        "local" if self.value(index + 1) == Some("function") => {}
+        "local" => {
+          let (bindings, next) = self.declare_list(index + 1);
+          if self.value(next) == Some("=") {
+            self.pending.push(Pending {
+              scope_count: self.scopes.len(),
+              depth: self.brackets.len(),
+              bindings,
+            });
+          } else {
+            self.bind(bindings);
+          }
+          skip_until = next;
+        }
+        "function" => {
+          let mut next = index + 1;
+          let is_local = index > 0
+            && self.value(index - 1) == Some("local");
+          if is_local && self.is_name(next) {
+            let old = self.tokens[next].value.to_string();
+            let new = self.fresh(0);
+            self.renames[next] = Some(new.clone());
+            self.bind(vec![(old, new)]);
+            next += 1;
+          }
+          let mut is_method = false;
+          while next < self.tokens.len()
+            && self.value(next) != Some("(")
+          {
+            is_method |= self.value(next) == Some(":");
+            if self.is_name(next) && !self.is_field(next) {
+              self.renames[next] = self.lookup(&self.tokens[next].value);
+            }
+            next += 1;
+          }
+          self.brackets.push("(");
+          self.push_scope();
+          if is_method {
+            self.bind(vec![("self".to_string(), "self".to_string())]);
+          }
+          let (bindings, after) = self.declare_list(next + 1);
+          self.bind(bindings);
+          skip_until = after;
+        }
+        _ if token.kind == TokenKind::Name
+          && !self.is_field(index) =>
+        {
+          self.renames[index] = self.lookup(value);
+        }
+        _ => {}
+      }
+      index += 1;
+    }
+  }
+}
+
+/// Renames local variables to the shortest names that do not
+/// collide with any other name in `tokens`, following Lua's
+/// block scoping rules. Globals and table fields are kept.
+pub fn rename_locals(tokens: &Tokens) -> Tokens {
+  let reserved = tokens
+    .iter()
+    .filter(|token| token.kind == TokenKind::Name)
+    .map(|token| token.value.to_string())
+    .chain(KEYWORDS.map(String::from))
+    .chain(["true", "false", "nil"].map(String::from))
+    .collect::<HashSet<_>>();
+  let mut renamer = Renamer {
+    tokens,
+    names: short_names(&reserved).take(tokens.len() + 1).collect(),
+    scopes: vec![vec![]],
+    renames: vec![None; tokens.len()],
+    pending: vec![],
+    loop_vars: vec![],
+    pending_pops: vec![],
+    brackets: vec![],
+  };
+  renamer.run();
+  tokens
+    .iter()
+    .zip(renamer.renames)
+    .map(|(token, rename)| match rename {
+      Some(value) => Token {
+        value: value.into(),
+        ..token.clone()
+      },
+      None => token.clone(),
+    })
+    .collect()
+}
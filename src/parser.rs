@@ -0,0 +1,528 @@
+use crate::lexer::{Location, Token, TokenKind, Tokens};
+
+const BINARY_OPERATORS: [&str; 21] = [
+  "+", "-", "*", "/", "//", "%", "^", "..", "==", "~=", "<",
+  "<=", ">", ">=", "and", "or", "&", "|", "~", "<<", ">>",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+  Name,
+  Literal,
+  Symbol,
+  Keyword,
+  Invalid,
+}
+
+struct Item<'a> {
+  value: &'a str,
+  class: Class,
+  location: &'a Location,
+}
+
+fn classify(tokens: &[Token]) -> Vec<Item<'_>> {
+  tokens
+    .iter()
+    .map(|token| Item {
+      value: &token.value,
+      class: match token.kind {
+        // Unexpanded invocations only reach the parser when
+        // measuring macro arguments, where they stand for the
+        // expressions they will expand to.
+        TokenKind::Name | TokenKind::Macro => Class::Name,
+        TokenKind::Keyword | TokenKind::Boolean | TokenKind::Nil => {
+          Class::Keyword
+        }
+        TokenKind::Number | TokenKind::String => Class::Literal,
+        TokenKind::Operator | TokenKind::Brace => Class::Symbol,
+        TokenKind::Delimiter
+          if &*token.value == "," || &*token.value == ";" =>
+        {
+          Class::Symbol
+        }
+        _ => Class::Invalid,
+      },
+      location: &token.location,
+    })
+    .collect()
+}
+
+struct Parser<'a, 'b> {
+  items: &'b [Item<'a>],
+  index: usize,
+}
+
+type ParseResult<T> = Result<T, (String, usize)>;
+
+impl Parser<'_, '_> {
+  fn peek(&self) -> Option<&str> {
+    self.items.get(self.index).map(|item| item.value)
+  }
+
+  fn peek_at(&self, offset: usize) -> Option<&str> {
+    self
+      .items
+      .get(self.index + offset)
+      .map(|item| item.value)
+  }
+
+  fn check(&self, value: &str) -> bool {
+    self.items.get(self.index).is_some_and(|item| {
+      item.value == value && item.class != Class::Literal
+    })
+  }
+
+  fn accept(&mut self, value: &str) -> bool {
+    if self.check(value) {
+      self.index += 1;
+      return true;
+    }
+    false
+  }
+
+  fn error<T>(&self, message: String) -> ParseResult<T> {
+    Err((message, self.index))
+  }
+
+  fn found(&self) -> String {
+    match self.peek() {
+      Some(value) => format!("`{}`", value),
+      None => "end of file".to_string(),
+    }
+  }
+
+  fn unexpected<T>(&self, expected: &str) -> ParseResult<T> {
+    self.error(format!("Expected {}, found {}", expected, self.found()))
+  }
+
+  fn expect(&mut self, value: &str) -> ParseResult<()> {
+    if self.accept(value) {
+      return Ok(());
+    }
+    self.unexpected(&format!("`{}`", value))
+  }
+
+  /// Expects the keyword closing the block opened by the
+  /// token at `opener`, mentioning it if missing.
+  fn expect_closing(
+    &mut self,
+    value: &str,
+    opener: usize,
+  ) -> ParseResult<()> {
+    if self.accept(value) {
+      return Ok(());
+    }
+    let opener = &self.items[opener];
+    self.error(format!(
+      "Expected `{}` to close `{}` at {}, found {}{}",
+      value,
+      opener.value,
+      opener.location,
+      self.found(),
+      opener.location.expansion_trace(),
+    ))
+  }
+
+  fn expect_name(&mut self) -> ParseResult<()> {
+    match self.items.get(self.index) {
+      Some(item) if item.class == Class::Name => {
+        self.index += 1;
+        Ok(())
+      }
+      _ => self.unexpected("a name"),
+    }
+  }
+
+  fn block_ends(&self) -> bool {
+    matches!(
+      self.peek(),
+      None | Some("end" | "else" | "elseif" | "until")
+    )
+  }
+
+  fn block(&mut self) -> ParseResult<()> {
+    while !self.block_ends() {
+      if self.accept("return") {
+        if !self.block_ends() && !self.check(";") {
+          self.expression_list()?;
+        }
+        self.accept(";");
+        if !self.block_ends() {
+          return self.unexpected("end of block after `return`");
+        }
+        break;
+      }
+      self.statement()?;
+    }
+    Ok(())
+  }
+
+  fn statement(&mut self) -> ParseResult<()> {
+    let Some(value) = self.peek().map(String::from) else {
+      return self.unexpected("a statement");
+    };
+    let item = &self.items[self.index];
+    if item.class == Class::Literal
+      || item.class != Class::Keyword
+        && item.class != Class::Name
+        && !["(", ";", "::"].contains(&item.value)
+    {
+      return self.unexpected("a statement");
+    }
+    let start = self.index;
+    match value.as_str() {
+      ";" | "break" => self.index += 1,
+      "::" => {
+        self.index += 1;
+        self.expect_name()?;
+        self.expect("::")?;
+      }
+      "goto" => {
+        self.index += 1;
+        self.expect_name()?;
+      }
+      "do" => {
+        self.index += 1;
+        self.block()?;
+        self.expect_closing("end", start)?;
+      }
+      "while" => {
+        self.index += 1;
+        self.expression()?;
+        self.expect("do")?;
+        self.block()?;
+        self.expect_closing("end", start)?;
+      }
+      "repeat" => {
+        self.index += 1;
+        self.block()?;
+        self.expect_closing("until", start)?;
+        self.expression()?;
+      }
+      "if" => {
+        self.index += 1;
+        self.expression()?;
+        self.expect("then")?;
+        self.block()?;
+        while self.accept("elseif") {
+          self.expression()?;
+          self.expect("then")?;
+          self.block()?;
+        }
+        if self.accept("else") {
+          self.block()?;
+        }
+        self.expect_closing("end", start)?;
+      }
+      "for" => {
+        self.index += 1;
+        self.expect_name()?;
+        if self.accept("=") {
+          self.expression()?;
+          self.expect(",")?;
+          self.expression()?;
+          if self.accept(",") {
+            self.expression()?;
+          }
+        } else {
+          while self.accept(",") {
+            self.expect_name()?;
+          }
+          self.expect("in")?;
+          self.expression_list()?;
+        }
+        self.expect("do")?;
+        self.block()?;
+        self.expect_closing("end", start)?;
+      }
+      "function" => {
+        self.index += 1;
+        self.expect_name()?;
+        while self.accept(".") {
+          self.expect_name()?;
+        }
+        if self.accept(":") {
+          self.expect_name()?;
+        }
+        self.function_body(start)?;
+      }
+      "local" => {
+        self.index += 1;
+        if self.accept("function") {
+          self.expect_name()?;
+          return self.function_body(start);
+        }
+        loop {
+          self.expect_name()?;
+          if self.accept("<") {
+            self.expect_name()?;
+            self.expect(">")?;
+          }
+          if !self.accept(",") {
+            break;
+          }
+        }
+        if self.accept("=") {
+          self.expression_list()?;
+        }
+      }
+      _ => self.expression_statement()?,
+    }
+    Ok(())
+  }
+
+  fn expression_statement(&mut self) -> ParseResult<()> {
+    let start = self.index;
+    let mut is_call = self.suffixed_expression()?;
+    if self.check("=") || self.check(",") {
+      while self.accept(",") {
+        is_call = self.suffixed_expression()?;
+        if is_call {
+          break;
+        }
+      }
+      if !is_call && self.accept("=") {
+        return self.expression_list();
+      }
+      return self.error("Cannot assign to a function call".into());
+    }
+    if !is_call {
+      return Err((
+        "Expected an assignment or function call statement"
+          .into(),
+        start,
+      ));
+    }
+    Ok(())
+  }
+
+  /// Parses a prefix expression followed by any number of
+  /// field accesses, indexings and calls, returning whether
+  /// it ended with a call.
+  fn suffixed_expression(&mut self) -> ParseResult<bool> {
+    if self.accept("(") {
+      self.expression()?;
+      self.expect(")")?;
+    } else {
+      self.expect_name()?;
+    }
+    let mut is_call = false;
+    loop {
+      if self.accept(".") {
+        self.expect_name()?;
+        is_call = false;
+      } else if self.accept("[") {
+        self.expression()?;
+        self.expect("]")?;
+        is_call = false;
+      } else if self.accept(":") {
+        self.expect_name()?;
+        self.arguments()?;
+        is_call = true;
+      } else if self.check("(")
+        || self.check("{")
+        || self.is_string()
+      {
+        self.arguments()?;
+        is_call = true;
+      } else {
+        return Ok(is_call);
+      }
+    }
+  }
+
+  fn is_string(&self) -> bool {
+    self.items.get(self.index).is_some_and(|item| {
+      item.class == Class::Literal
+        && (item.value.starts_with('"')
+          || item.value.starts_with('\'')
+          || item.value.starts_with('['))
+    })
+  }
+
+  fn arguments(&mut self) -> ParseResult<()> {
+    if self.is_string() {
+      self.index += 1;
+      return Ok(());
+    }
+    if self.check("{") {
+      return self.table();
+    }
+    self.expect("(")?;
+    if !self.accept(")") {
+      self.expression_list()?;
+      self.expect(")")?;
+    }
+    Ok(())
+  }
+
+  fn table(&mut self) -> ParseResult<()> {
+    self.expect("{")?;
+    while !self.accept("}") {
+      if self.accept("[") {
+        self.expression()?;
+        self.expect("]")?;
+        self.expect("=")?;
+      } else if self.items.get(self.index).is_some_and(|item| {
+        item.class == Class::Name
+      }) && self.peek_at(1) == Some("=")
+      {
+        self.index += 2;
+      }
+      self.expression()?;
+      if !self.accept(",") && !self.accept(";") {
+        return self.expect("}");
+      }
+    }
+    Ok(())
+  }
+
+  fn function_body(&mut self, opener: usize) -> ParseResult<()> {
+    self.expect("(")?;
+    if !self.accept(")") {
+      loop {
+        if self.accept("...") {
+          break;
+        }
+        self.expect_name()?;
+        if !self.accept(",") {
+          break;
+        }
+      }
+      self.expect(")")?;
+    }
+    self.block()?;
+    self.expect_closing("end", opener)
+  }
+
+  fn expression_list(&mut self) -> ParseResult<()> {
+    self.expression()?;
+    while self.accept(",") {
+      self.expression()?;
+    }
+    Ok(())
+  }
+
+  fn expression(&mut self) -> ParseResult<()> {
+    loop {
+      while ["not", "-", "#", "~"].iter().any(|op| self.check(op))
+      {
+        self.index += 1;
+      }
+      self.simple_expression()?;
+      let is_binary = self.items.get(self.index).is_some_and(|item| {
+        item.class != Class::Literal
+          && BINARY_OPERATORS.contains(&item.value)
+      });
+      if !is_binary {
+        return Ok(());
+      }
+      self.index += 1;
+    }
+  }
+
+  fn simple_expression(&mut self) -> ParseResult<()> {
+    let Some(item) = self.items.get(self.index) else {
+      return self.unexpected("an expression");
+    };
+    let is_prefix = item.class == Class::Name || item.value == "(";
+    if item.class == Class::Literal
+      || ["nil", "true", "false", "..."].contains(&item.value)
+    {
+      self.index += 1;
+      return Ok(());
+    }
+    if self.accept("function") {
+      return self.function_body(self.index - 1);
+    }
+    if self.check("{") {
+      return self.table();
+    }
+    if is_prefix {
+      self.suffixed_expression()?;
+      return Ok(());
+    }
+    self.unexpected("an expression")
+  }
+}
+
+/// Checks that `tokens` form a valid Lua 5.4 chunk. Errors
+/// point at the offending token and list the macro
+/// invocations it was expanded from.
+pub fn check(tokens: &Tokens) -> Result<(), String> {
+  let items = classify(tokens);
+  let mut parser = Parser {
+    items: &items,
+    index: 0,
+  };
+  let result = parser.block().and_then(|_| match parser.peek() {
+    None => Ok(()),
+    Some(_) => parser.unexpected("end of file"),
+  });
+  let Err((message, index)) = result else {
+    return Ok(());
+  };
+  let item = parser.items.get(index).or(parser.items.last());
+  Err(match item {
+    Some(item) => format!(
+      "{}: Syntax error: {}{}{}",
+      item.location,
+      message,
+      item.location.excerpt(),
+      item.location.expansion_trace(),
+    ),
+    None => format!("Syntax error: {}", message),
+  })
+}
+
+/// Finds where Lua fragments starting at a given token end,
+/// for matching macro patterns against an invocation.
+pub struct Measure<'a> {
+  items: Vec<Item<'a>>,
+}
+
+impl<'a> Measure<'a> {
+  pub fn new(tokens: &'a [Token]) -> Self {
+    Measure {
+      items: classify(tokens),
+    }
+  }
+
+  /// Returns the index after the longest expression starting
+  /// at `start`, if there is one.
+  pub fn expression(&self, start: usize) -> Option<usize> {
+    let mut parser = Parser {
+      items: &self.items,
+      index: start,
+    };
+    parser.expression().ok()?;
+    Some(parser.index)
+  }
+
+  /// Returns the index after the statements starting at
+  /// `start`, up to the first one that does not parse or a
+  /// keyword that ends a block.
+  pub fn block(&self, start: usize) -> usize {
+    let mut parser = Parser {
+      items: &self.items,
+      index: start,
+    };
+    while !parser.block_ends() {
+      let statement = parser.index;
+      if parser.accept("return") {
+        if !parser.block_ends()
+          && !parser.check(";")
+          && parser.expression_list().is_err()
+        {
+          return statement;
+        }
+        parser.accept(";");
+        break;
+      }
+      if parser.statement().is_err() {
+        return statement;
+      }
+    }
+    parser.index
+  }
+}
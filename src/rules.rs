@@ -0,0 +1,502 @@
+use crate::lexer::{Location, Token, TokenKind, Tokens};
+use crate::parser::Measure;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// What a capture in a pattern matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fragment {
+  /// A single name.
+  Name,
+  /// A single number, string, boolean or `nil`.
+  Literal,
+  /// A single token, or a bracketed group of tokens.
+  Tt,
+  /// The longest Lua expression.
+  Expr,
+  /// Any number of Lua statements.
+  Block,
+}
+
+const FRAGMENTS: [(&str, Fragment); 5] = [
+  ("name", Fragment::Name),
+  ("literal", Fragment::Literal),
+  ("tt", Fragment::Tt),
+  ("expr", Fragment::Expr),
+  ("block", Fragment::Block),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Repetition {
+  ZeroOrMore,
+  OneOrMore,
+  ZeroOrOne,
+}
+
+#[derive(Debug)]
+pub enum Matcher {
+  /// A token that must appear as is.
+  Token(Rc<str>),
+  Capture {
+    name: String,
+    fragment: Fragment,
+  },
+  Repeat {
+    matchers: Vec<Matcher>,
+    separator: Option<Rc<str>>,
+    repetition: Repetition,
+  },
+}
+
+#[derive(Debug)]
+pub struct Rule {
+  pub pattern: Vec<Matcher>,
+  /// The pattern as written, to list in errors.
+  pub source: String,
+  pub body: Tokens,
+  pub location: Location,
+}
+
+impl Rule {
+  pub fn capture_names(&self) -> Vec<String> {
+    capture_names(&self.pattern)
+  }
+}
+
+/// A macro defined by `#rules`, which expands the body of
+/// the first rule whose pattern matches the invocation.
+#[derive(Debug)]
+pub struct RulesMacro {
+  pub name: String,
+  pub rules: Vec<Rule>,
+}
+
+/// The tokens a capture matched, once per repetition for
+/// captures inside `$(...)`.
+#[derive(Debug, Clone)]
+pub enum Capture {
+  One(Tokens),
+  Many(Vec<Capture>),
+}
+
+pub type Captures = HashMap<String, Capture>;
+
+fn is_dollar(token: &Token) -> bool {
+  token.kind == TokenKind::Special && &*token.value == "$"
+}
+
+fn is_opener(token: &Token) -> bool {
+  token.kind == TokenKind::Brace
+    && matches!(&*token.value, "(" | "[" | "{")
+}
+
+/// Returns the tokens inside the group opened at `index`.
+fn group_at(tokens: &[Token], index: usize) -> Option<&[Token]> {
+  if !is_opener(tokens.get(index)?) {
+    return None;
+  }
+  let mut depth = 0;
+  for (end, token) in tokens.iter().enumerate().skip(index) {
+    if token.kind != TokenKind::Brace {
+      continue;
+    }
+    if is_opener(token) {
+      depth += 1;
+    } else {
+      depth -= 1;
+      if depth == 0 {
+        return Some(&tokens[index + 1..end]);
+      }
+    }
+  }
+  None
+}
+
+/// Parses the separator and operator following `$(...)`.
+fn repetition(
+  tokens: &[Token],
+  index: &mut usize,
+) -> Option<(Option<Token>, Repetition)> {
+  let operator = |token: Option<&Token>| match token.map(|t| &*t.value) {
+    Some("*") => Some(Repetition::ZeroOrMore),
+    Some("+") => Some(Repetition::OneOrMore),
+    Some("?") => Some(Repetition::ZeroOrOne),
+    _ => None,
+  };
+  if let Some(repetition) = operator(tokens.get(*index)) {
+    *index += 1;
+    return Some((None, repetition));
+  }
+  let separator = tokens.get(*index)?.clone();
+  let repetition = operator(tokens.get(*index + 1))?;
+  *index += 2;
+  Some((Some(separator), repetition))
+}
+
+fn parse_pattern(tokens: &[Token]) -> Result<Vec<Matcher>, String> {
+  let mut matchers = vec![];
+  let mut index = 0;
+  while let Some(token) = tokens.get(index) {
+    index += 1;
+    if !is_dollar(token) {
+      matchers.push(Matcher::Token(token.value.clone()));
+      continue;
+    }
+    match tokens.get(index) {
+      Some(name) if name.kind == TokenKind::Name => {
+        let fragment = match (tokens.get(index + 1), tokens.get(index + 2)) {
+          (Some(colon), Some(spec)) if &*colon.value == ":" => FRAGMENTS
+            .iter()
+            .find(|(fragment, _)| *fragment == &*spec.value)
+            .map(|(_, fragment)| *fragment),
+          _ => None,
+        }
+        .ok_or(format!(
+          "{}: Expected a fragment specifier after `${}`, one of {}",
+          name.location,
+          name.value,
+          FRAGMENTS
+            .iter()
+            .map(|(fragment, _)| format!("`{}`", fragment))
+            .collect::<Vec<_>>()
+            .join(", "),
+        ))?;
+        index += 3;
+        matchers.push(Matcher::Capture {
+          name: name.value.to_string(),
+          fragment,
+        });
+      }
+      Some(_) => {
+        let inner = group_at(tokens, index).ok_or(format!(
+          "{}: Expected a capture or a repetition after `$`",
+          token.location,
+        ))?;
+        index += inner.len() + 2;
+        let (separator, repetition) = repetition(tokens, &mut index)
+          .ok_or(format!(
+            "{}: Expected `*`, `+` or `?` after the repetition",
+            token.location,
+          ))?;
+        matchers.push(Matcher::Repeat {
+          matchers: parse_pattern(inner)?,
+          separator: separator.map(|separator| separator.value),
+          repetition,
+        });
+      }
+      None => {
+        return Err(format!(
+          "{}: Expected a capture or a repetition after `$`",
+          token.location,
+        ))
+      }
+    }
+  }
+  Ok(matchers)
+}
+
+fn capture_names(matchers: &[Matcher]) -> Vec<String> {
+  matchers
+    .iter()
+    .flat_map(|matcher| match matcher {
+      Matcher::Token(_) => vec![],
+      Matcher::Capture { name, .. } => vec![name.clone()],
+      Matcher::Repeat { matchers, .. } => capture_names(matchers),
+    })
+    .collect()
+}
+
+/// Quotes `tokens` from their source file, falling back to
+/// joining them if they were not all read from the same one.
+fn source_text(tokens: &[Token]) -> String {
+  let text = match (tokens.first(), tokens.last()) {
+    (Some(first), Some(last)) if first.location.file == last.location.file => {
+      first.location.file.source().and_then(|source| {
+        let span = first.location.span.start as usize
+          ..last.location.span.end as usize;
+        source.get(span).map(String::from)
+      })
+    }
+    _ => None,
+  };
+  text.unwrap_or_else(|| {
+    tokens
+      .iter()
+      .map(|token| &*token.value)
+      .collect::<Vec<_>>()
+      .join(" ")
+  })
+}
+
+/// Parses the rules of `#rules name!`, each written as a
+/// bracketed pattern, `=>` and a bracketed body.
+pub fn parse_rules(
+  name: &Token,
+  tokens: &[Token],
+) -> Result<RulesMacro, String> {
+  let mut rules = vec![];
+  let mut index = 0;
+  while let Some(token) = tokens.get(index) {
+    if &*token.value == ";" {
+      index += 1;
+      continue;
+    }
+    let start = index;
+    let source = group_at(tokens, index).ok_or(format!(
+      "{}: Expected a bracketed pattern in the rules of `{}`",
+      token.location, name.value,
+    ))?;
+    index += source.len() + 2;
+    tokens
+      .get(index)
+      .filter(|arrow| &*arrow.value == "=>")
+      .ok_or(format!("{}: Expected `=>` after the pattern", token.location))?;
+    let body = group_at(tokens, index + 1).ok_or(format!(
+      "{}: Expected a bracketed body after `=>`",
+      tokens[index].location,
+    ))?;
+    index += body.len() + 3;
+    rules.push(Rule {
+      pattern: parse_pattern(source)?,
+      source: source_text(&tokens[start..index - body.len() - 3]),
+      body: body.to_vec(),
+      location: token.location.clone(),
+    });
+  }
+  if rules.is_empty() {
+    return Err(format!("{}: `{}` has no rules", name.location, name.value));
+  }
+  Ok(RulesMacro {
+    name: name.value.to_string(),
+    rules,
+  })
+}
+
+/// Returns the index after the fragment starting at `start`,
+/// if one starts there.
+fn match_fragment(
+  fragment: Fragment,
+  tokens: &[Token],
+  start: usize,
+  measure: &Measure,
+) -> Option<usize> {
+  let token = tokens.get(start);
+  match fragment {
+    Fragment::Name => {
+      token.filter(|token| token.kind == TokenKind::Name)?;
+      Some(start + 1)
+    }
+    Fragment::Literal => {
+      token.filter(|token| {
+        matches!(
+          token.kind,
+          TokenKind::Number
+            | TokenKind::String
+            | TokenKind::Boolean
+            | TokenKind::Nil
+        )
+      })?;
+      Some(start + 1)
+    }
+    Fragment::Tt => match group_at(tokens, start) {
+      Some(group) => Some(start + group.len() + 2),
+      None if token?.kind == TokenKind::Brace => None,
+      None => Some(start + 1),
+    },
+    Fragment::Expr => measure.expression(start),
+    Fragment::Block => Some(measure.block(start)),
+  }
+}
+
+/// Matches `matchers` against the tokens from `index` on,
+/// advancing it past them. Fragments are matched greedily,
+/// and repetitions as often as possible, without
+/// backtracking.
+fn match_pattern(
+  matchers: &[Matcher],
+  tokens: &[Token],
+  index: &mut usize,
+  measure: &Measure,
+  captures: &mut Captures,
+) -> bool {
+  for matcher in matchers {
+    match matcher {
+      Matcher::Token(value) => {
+        if tokens.get(*index).is_none_or(|token| token.value != *value) {
+          return false;
+        }
+        *index += 1;
+      }
+      Matcher::Capture { name, fragment } => {
+        let Some(end) = match_fragment(*fragment, tokens, *index, measure)
+        else {
+          return false;
+        };
+        captures.insert(
+          name.clone(),
+          Capture::One(tokens[*index..end].to_vec()),
+        );
+        *index = end;
+      }
+      Matcher::Repeat {
+        matchers,
+        separator,
+        repetition,
+      } => {
+        let mut matches = vec![];
+        loop {
+          let mut next = *index;
+          if let (false, Some(separator)) = (matches.is_empty(), separator) {
+            if tokens.get(next).is_none_or(|t| t.value != *separator) {
+              break;
+            }
+            next += 1;
+          }
+          let start = next;
+          let mut inner = Captures::new();
+          if !match_pattern(matchers, tokens, &mut next, measure, &mut inner)
+            || next == start
+          {
+            break;
+          }
+          matches.push(inner);
+          *index = next;
+          if *repetition == Repetition::ZeroOrOne {
+            break;
+          }
+        }
+        if *repetition == Repetition::OneOrMore && matches.is_empty() {
+          return false;
+        }
+        for name in capture_names(matchers) {
+          let each = matches
+            .iter_mut()
+            .map(|inner| {
+              inner.remove(&name).unwrap_or(Capture::Many(vec![]))
+            })
+            .collect();
+          captures.insert(name, Capture::Many(each));
+        }
+      }
+    }
+  }
+  true
+}
+
+/// Picks the first rule whose pattern matches all of `input`.
+pub fn select_rule<'a>(
+  rules_macro: &'a RulesMacro,
+  input: &[Token],
+) -> Option<(&'a Rule, Captures)> {
+  let measure = Measure::new(input);
+  rules_macro.rules.iter().find_map(|rule| {
+    let mut index = 0;
+    let mut captures = Captures::new();
+    let matched =
+      match_pattern(&rule.pattern, input, &mut index, &measure, &mut captures);
+    (matched && index == input.len()).then_some((rule, captures))
+  })
+}
+
+/// The names of the captures `tokens` use.
+fn used_captures(tokens: &[Token]) -> impl Iterator<Item = &str> {
+  tokens
+    .windows(2)
+    .filter(|pair| is_dollar(&pair[0]))
+    .map(|pair| &*pair[1].value)
+}
+
+/// How many times the `$(...)` around `tokens` repeats: as
+/// many times as the repeated captures it uses matched.
+fn repeat_count(
+  tokens: &[Token],
+  captures: &Captures,
+  location: &Location,
+) -> Result<usize, String> {
+  let mut count = None;
+  for name in used_captures(tokens) {
+    let Some(Capture::Many(each)) = captures.get(name) else {
+      continue;
+    };
+    if count.is_some_and(|count| count != each.len()) {
+      return Err(format!(
+        "{}: Captures repeated inside the same `$(...)` matched different numbers of times{}",
+        location,
+        location.expansion_trace(),
+      ));
+    }
+    count = Some(each.len());
+  }
+  count.ok_or(format!(
+    "{}: `$(...)` uses no repeated capture{}",
+    location,
+    location.expansion_trace(),
+  ))
+}
+
+/// Writes out a rule body, replacing `$name` by what the
+/// capture matched and repeating each `$(...)` once per match
+/// of the captures inside it.
+pub fn transcribe(
+  body: &[Token],
+  captures: &Captures,
+  out: &mut Tokens,
+) -> Result<(), String> {
+  let mut index = 0;
+  while let Some(token) = body.get(index) {
+    index += 1;
+    if !is_dollar(token) {
+      out.push(token.clone());
+      continue;
+    }
+    let trace = token.location.expansion_trace();
+    match body.get(index) {
+      Some(name) if name.kind == TokenKind::Name => {
+        index += 1;
+        match captures.get(&*name.value) {
+          Some(Capture::One(tokens)) => out.extend(tokens.iter().cloned()),
+          Some(Capture::Many(_)) => {
+            return Err(format!(
+              "{}: `${}` is repeated, so it must be used inside `$(...)`{}",
+              name.location, name.value, trace,
+            ))
+          }
+          None => {
+            return Err(format!(
+              "{}: `${}` is not captured by the pattern{}",
+              name.location, name.value, trace,
+            ))
+          }
+        }
+      }
+      _ => {
+        let inner = group_at(body, index).ok_or(format!(
+          "{}: Expected a capture or a repetition after `$`{}",
+          token.location, trace,
+        ))?;
+        index += inner.len() + 2;
+        let (separator, _) = repetition(body, &mut index).ok_or(format!(
+          "{}: Expected `*`, `+` or `?` after the repetition{}",
+          token.location, trace,
+        ))?;
+        let count = repeat_count(inner, captures, &token.location)?;
+        for i in 0..count {
+          if let (true, Some(separator)) = (i > 0, &separator) {
+            out.push(separator.clone());
+          }
+          let each = used_captures(inner)
+            .filter_map(|name| {
+              let capture = match captures.get(name)? {
+                Capture::Many(each) => each[i].clone(),
+                capture => capture.clone(),
+              };
+              Some((name.to_string(), capture))
+            })
+            .collect();
+          transcribe(inner, &each, out)?;
+        }
+      }
+    }
+  }
+  Ok(())
+}
@@ -2,15 +2,28 @@ use crate::lexer::*;
 
 #[cfg(test)]
 mod tests {
+  use super::*;
+  use crate::files;
+  use crate::format::format_tokens;
+  use crate::minify::{rename_locals, render_minified};
+  use crate::parser::check;
+  use crate::lua;
+  use crate::macros::Macros;
+  use crate::{apply_pastes, process_tokens, render_tokens, replace_tokens};
+
+  /// Expands `code` after the definitions in `source`, and
+  /// applies the pastes, as compiling does.
+  fn expand(source: &str, code: &str) -> Result<String, String> {
+    let tokens = lex("<stdin>", &(source.to_string() + code)).unwrap();
+    process_tokens(tokens, &mut Macros::default(), &lua::Options::default())
+      .map(|tokens| render_tokens(apply_pastes(tokens)))
+  }
+
   fn new_token(kind: TokenKind, value: &str) -> Token {
     Token {
       kind,
-      value: value.to_string(),
-      location: Location {
-        file: "".to_string(),
-        line: 0,
-        column: 0,
-      },
+      value: value.into(),
+      location: Location::default(),
     }
   }
 
@@ -18,11 +31,11 @@ mod tests {
   fn test_replace_tokens() {
     let tokens = lex("<stdin>", "
       print(sum)
-    ");
+    ").unwrap();
     let old = new_token(TokenKind::Name, "sum");
     let new = vec![
       new_token(TokenKind::Number, "1"),
-      new_token(TokenKind::Special, "+"),
+      new_token(TokenKind::Operator, "+"),
       new_token(TokenKind::Number, "2"),
     ];
     let result = replace_tokens(tokens, old, new);
@@ -31,6 +44,720 @@ mod tests {
       "print ( 1 + 2 )",
     );
   }
-}
 
+  #[test]
+  fn test_format_tokens() {
+    let tokens = lex("<stdin>", "
+      local x = 1 local function f(a, b)
+      if a then return -b else return { } end end
+      for i = 1, x do print(i, #t) end
+    ").unwrap();
+    assert_eq!(
+      format_tokens(&tokens, 2),
+      "local x = 1\n\
+       local function f(a, b)\n\
+       \x20 if a then\n\
+       \x20   return -b\n\
+       \x20 else\n\
+       \x20   return {}\n\
+       \x20 end\n\
+       end\n\
+       for i = 1, x do\n\
+       \x20 print(i, #t)\n\
+       end\n",
+    );
+    let tokens = lex("<stdin>", "goto l ::l:: ::m:: do end x = a ::n::")
+      .unwrap();
+    assert_eq!(
+      format_tokens(&tokens, 2),
+      "goto l\n:: l ::\n:: m ::\ndo\nend\nx = a\n:: n ::\n",
+    );
+  }
+
+  #[test]
+  fn test_minify_tokens() {
+    let tokens = lex("<stdin>", "
+      local count = 1 --[[ gone ]]
+      local function f(count)
+        return { count = count, x = a.count } - -1
+      end
+      print(f(count))
+    ").unwrap();
+    assert_eq!(
+      render_minified(&rename_locals(&tokens)),
+      "local b=1 local function c(d)return{count=d,x=a.count}- -1 \
+       end print(c(b))\n",
+    );
+  }
+
+  #[test]
+  fn test_check_syntax() {
+    let valid = lex("<stdin>", "
+      local t <const> = { 1, x = 2, [3] = 4; }
+      for k, v in pairs(t) do print(k, v) end
+      local f = function(...) return select('#', ...) end
+      t.x, t[1] = f(1), -2 ^ 2
+      repeat goto done until true ::done::
+      obj:method \"arg\" { } print [[long]]
+    ").unwrap();
+    assert!(check(&valid).is_ok());
+    let invalid = lex("<stdin>", "
+      if x then print(x)
+      f() = 1
+    ").unwrap();
+    assert!(check(&invalid).is_err());
+  }
+
+  /// Renders `code` expanded, numbering the suffixes of the
+  /// renamed identifiers in order of appearance.
+  fn expand_hygienic(code: &str) -> String {
+    let rendered = expand("", code).unwrap();
+    let mut ids: Vec<String> = vec![];
+    regex::Regex::new(r"__\d+")
+      .unwrap()
+      .replace_all(&rendered, |captures: &regex::Captures| {
+        let id = captures[0].to_string();
+        let index = ids.iter().position(|seen| *seen == id);
+        let index = index.unwrap_or_else(|| {
+          ids.push(id);
+          ids.len() - 1
+        });
+        format!("__{}", index + 1)
+      })
+      .into_owned()
+  }
+
+  #[test]
+  fn test_hygienic_locals() {
+    assert_eq!(
+      expand_hygienic("
+        #define each!(list, f) =
+          for i, value in ipairs(list) do f(value, i) end
+          #gensym total
+          total = #list
+        #end
+        local i = 1
+        each!(items, function(v) print(v, i) end)
+        each!(items, print)
+      "),
+      "local i = 1 \
+       for i__1 , value__1 in ipairs ( items ) do \
+       function ( v ) print ( v , i ) end ( value__1 , i__1 ) end \
+       total__1 = # items \
+       for i__2 , value__2 in ipairs ( items ) do \
+       print ( value__2 , i__2 ) end total__2 = # items",
+    );
+    assert_eq!(
+      expand_hygienic("
+        #define m!(a) =
+          do
+            if a then local v = 1 print(v)
+            elseif b then print(v)
+            else local w = v print(w) end
+            v = 2
+          end
+        #end
+        m!(x)
+      "),
+      "do if x then local v__1 = 1 print ( v__1 ) \
+       elseif b then print ( v ) \
+       else local w__1 = v print ( w__1 ) end v = 2 end",
+    );
+  }
+
+  #[test]
+  fn test_recursive_macros() {
+    let error = expand(
+      "#define a! = b!(1) #end\n#define b!(x) = { x, a! } #end\n",
+      "print(a!)",
+    )
+    .unwrap_err();
+    assert!(error.contains("a! -> b! -> a!"));
+    let source = "#define f!(x) = (x + 1) #end\n";
+    let code = "print(f!(f!(1)))";
+    assert_eq!(
+      expand(source, code),
+      Ok("print ( ( ( 1 + 1 ) + 1 ) )".to_string()),
+    );
+    let tokens = lex("<stdin>", &(source.to_string() + code)).unwrap();
+    let options = lua::Options::default();
+    let mut macros = Macros::default();
+    macros.limits.max_expansions = 1;
+    assert!(process_tokens(tokens.clone(), &mut macros, &options).is_err());
+    let mut macros = Macros::default();
+    macros.limits.max_depth = 1;
+    assert!(process_tokens(tokens, &mut macros, &options).is_err());
+    let error = expand("#define call!(f) = f##!(f) #end\n", "call!(call)")
+      .unwrap_err();
+    assert!(error.contains("call! -> call!"));
+  }
+
+  #[test]
+  fn test_vararg_builtins() {
+    let source = "
+      #define printf!(fmt, #...) =
+        print(fmt:format(__VA_OPT__(#...)), __VA_COUNT__)
+      #end
+      #define second!(#...) = __VA_ARG__(2) #end
+      #define tail!(#...) = { __VA_REST__(2) } #end
+      #define count!(x, #...) = { x, __VA_COUNT__, #... } #end
+    ";
+    assert_eq!(
+      expand(
+        source,
+        "printf!(s) printf!(s, 1, x) \
+         second!(a, { b, c }) tail!(1, 2, 3) tail!()",
+      ),
+      Ok(
+        "print ( s : format ( ) , 0 ) print ( s : format ( 1 , x ) , 2 ) \
+         { b , c } { 2 , 3 } { }"
+          .to_string()
+      ),
+    );
+    assert!(expand(source, "second!(a)").is_err());
+    assert_eq!(
+      expand(source, "count!(__VA_COUNT__, __VA_ARG__, __VA_OPT__)"),
+      Ok("{ __VA_COUNT__ , 2 , __VA_ARG__ , __VA_OPT__ }".to_string()),
+    );
+  }
+
+  #[test]
+  fn test_default_params() {
+    let source = "
+      #define log!(msg, level = \"INFO\", out = io.write) =
+        out(level, msg)
+      #end
+    ";
+    assert_eq!(
+      expand(source, "log!(x) log!(x, \"WARN\") log!(x, , f)"),
+      Ok(
+        "io . write ( \"INFO\" , x ) io . write ( \"WARN\" , x ) \
+         f ( \"INFO\" , x )"
+          .to_string()
+      ),
+    );
+    let error = expand(source, "log!()").unwrap_err();
+    assert!(error.contains("Missing argument `msg`"));
+  }
+
+  #[test]
+  fn test_named_args() {
+    let source = "
+      #define window!(title, width = 640, height = 480) =
+        open(title, width, height)
+      #end
+      #define run!(stmt) = stmt #end
+    ";
+    assert_eq!(
+      expand(source, "window!(t, height = 1) window!(height = 2, title = u)"),
+      Ok("open ( t , 640 , 1 ) open ( u , 640 , 2 )".to_string()),
+    );
+    assert_eq!(expand(source, "run!(x = 1)"), Ok("x = 1".to_string()));
+    for invalid in [
+      "window!(t, title = u)",
+      "window!(width = 1, width = 2)",
+      "window!(width = 1, t)",
+      "window!(width = 1, depth = 2)",
+    ] {
+      assert!(expand(source, invalid).is_err());
+    }
+  }
+
+  #[test]
+  fn test_overloads() {
+    let source = "
+      #define area!(r) = r * r #end
+      #define area!(w, h) = w * h #end
+      #define area!(w, h, #...) = w * h * __VA_COUNT__ #end
+      #define area!(side) = side #end
+    ";
+    assert_eq!(
+      expand(source, "area!(2) area!(2, 3,) area!(1, 2, 3, 4)"),
+      Ok("2 * 2 2 * 3 1 * 2 * 2".to_string()),
+    );
+    let error = expand(source, "area!()").unwrap_err();
+    assert!(error.contains("area!(r) at <stdin>:2:15"));
+    assert!(error.contains("area!(w, h, #...) at <stdin>:4:15"));
+  }
+
+  #[test]
+  fn test_declared_separators() {
+    let source = "
+      #define for_each!(item in list) = for _, item in ipairs(list) do #end
+      #define pair!(a, b) = {a, b} #end
+    ";
+    assert_eq!(
+      expand(source, "for_each!(x in f(a, b)) end pair!(1 => 2)"),
+      Ok("for _ , x in ipairs ( f ( a , b ) ) do end { 1 , 2 }".to_string()),
+    );
+    assert_eq!(
+      expand(source, "for_each!(x, items)"),
+      Err(
+        "<stdin>:4:16: Expected `in` between the arguments of `for_each!`, \
+         found `,`"
+          .to_string()
+      ),
+    );
+    assert_eq!(
+      expand(source, "pair!(a is b, c)"),
+      Ok("{ a is b , c }".to_string()),
+    );
+  }
+
+  #[test]
+  fn test_for_loops() {
+    let source = "
+      #define fields!(t, #...) =
+        #for i, field in #...
+          #for suffix in Min, Max t.field##suffix = i #endfor
+        #endfor
+      #end
+    ";
+    assert_eq!(
+      expand(
+        source,
+        "#for x in a, (b.c), {d} f(x) #endfor \
+         fields!(self, x, y) \
+         #for x in #endfor",
+      ),
+      Ok(
+        "f ( a ) f ( b . c ) f ( { d } ) \
+         self . xMin = 1 self . xMax = 1 self . yMin = 2 self . yMax = 2"
+          .to_string()
+      ),
+    );
+    assert!(expand(source, "#for x in a, b f(x)").is_err());
+  }
+
+  #[test]
+  fn test_eval() {
+    let source = "
+      #define WIDTH! = 800 #end
+      #define NAME! = 'app' #end
+    ";
+    assert_eq!(
+      expand(
+        source,
+        "eval!(WIDTH! * 2 + 1) eval!(-7 // 2) eval!(7 / 2) eval!(2 ^ 2)",
+      ),
+      Ok("1601 -4 3.5 4.0".to_string()),
+    );
+    assert_eq!(
+      expand(
+        source,
+        "eval!(NAME! .. '\\t' .. WIDTH!) eval!(#NAME! > 2 and 1 | 2)",
+      ),
+      Ok("\"app\\t800\" 3".to_string()),
+    );
+    let error = expand(source, "eval!(1 + NAME!)").unwrap_err();
+    assert!(error.starts_with("<stdin>:3:23: Attempt to perform arithmetic"));
+    assert!(error.contains("in expansion of `NAME!` at <stdin>:4:15"));
+    assert!(expand(source, "eval!(1 // 0)").is_err());
+    assert_eq!(
+      expand(
+        source,
+        "eval!(1 / 3 .. '') eval!(2 ^ 53 .. '') eval!(2.0 .. '') \
+         eval!('10' + 1) eval!(-'0x10') eval!('3' & 1)",
+      ),
+      Ok("\"0.33333333333333\" \"9.007199254741e+15\" \"2.0\" \
+          11 -16 1".to_string()),
+    );
+    assert!(expand(source, "eval!('inf' + 1)").is_err());
+    assert_eq!(
+      expand(
+        source,
+        "eval!(1 / 0) eval!(-1 / 0) eval!(0 / 0) eval!(0x1p4) \
+         eval!(0x.8 + 0) eval!(0xA.8p1) eval!('0x1P-1' * 1) eval!(0xff)",
+      ),
+      Ok("( 1 / 0 ) ( - 1 / 0 ) ( 0 / 0 ) 16.0 0.5 21.0 0.5 255".to_string()),
+    );
+  }
+
+  #[test]
+  fn test_inspect_args() {
+    let source = "
+      #define show!(x) =
+        #if is_name!(x) name(x)
+        #else
+          #if (is_string!(x) or is_number!(x)) literal(x)
+          #else
+            #if is_table!(x) table(token_count!(x)) #else other(#x#) #endif
+          #endif
+        #endif
+      #end
+      #define opt!(#...) = #if not is_empty!(#...) f(#...) #endif #end
+    ";
+    assert_eq!(
+      expand(
+        source,
+        "show!(a) show!('s') show!(-1) show!({ 1 }) show!({} .. {}) \
+         opt!() opt!(2)",
+      ),
+      Ok(
+        "name ( a ) literal ( 's' ) literal ( - 1 ) table ( 3 ) \
+         other ( \"{ } .. { }\" ) f ( 2 )"
+          .to_string()
+      ),
+    );
+  }
+
+  #[test]
+  fn test_rules_macros() {
+    let source = "
+      #rules record!
+        () => { {} }
+        ($($key:name = $value:expr),+) => { { $($key = $value),* } }
+      #end
+      #rules unless!
+        ($cond:expr then $body:block) => { if not ($cond) then $body end }
+      #end
+    ";
+    assert_eq!(
+      expand(source, "record!() record!(a = f(1, 2), b = {x})"),
+      Ok("{ } { a = f ( 1 , 2 ) , b = { x } }".to_string()),
+    );
+    assert_eq!(
+      expand(source, "unless!(a > b then print(a) a = b)"),
+      Ok("if not ( a > b ) then print ( a ) a = b end".to_string()),
+    );
+    let error = expand(source, "record!(1)").unwrap_err();
+    assert!(error.contains("No rule of `record!` matches `1`"));
+    assert!(error.contains("($($key:name = $value:expr),+) at <stdin>:4:9"));
+  }
+
+  #[test]
+  fn test_scoped_macros() {
+    let code = "
+      #define A! = 1 #end
+      #push_macro A!
+      #undef A!
+      #define A! = 2 #end
+      #push_macro B!
+      #define B! = 3 #end
+      a(A!, B!)
+      #pop_macro B!
+      #pop_macro A!
+      #scope
+        #define twice!(x) = x x #end
+        #undef A!
+        b(twice!(1))
+        #scope #define C! = 4 #end c(C!) #endscope
+        #ifdef C! d() #endif
+      #endscope
+      #ifdef twice! e() #endif
+      #ifndef B! f(A!) #endif
+    ";
+    assert_eq!(
+      expand("", code),
+      Ok("a ( 2 , 3 ) b ( 1 1 ) c ( 4 ) f ( 1 )".to_string()),
+    );
+    for invalid in ["#pop_macro A!", "#scope x = 1", "x = 1 #endscope"] {
+      assert!(expand("", invalid).is_err());
+    }
+  }
 
+  #[test]
+  fn test_namespaces() {
+    let source = "
+      #namespace std
+        #define wrap!(x) = (x) #end
+        #define class!(name) = local name = wrap!({}) #end
+        #namespace inner #define deep! = wrap!(1) #end #endnamespace
+      #endnamespace
+      #namespace net #define log!(msg) = send(msg) #end #endnamespace
+      #define log!(msg) = print(msg) #end
+    ";
+    assert_eq!(
+      expand(
+        source,
+        "std.class!(A) net::log!(1) log!(2) std::inner.deep! t.log!(3)",
+      ),
+      Ok("local A = ( { } ) send ( 1 ) print ( 2 ) ( 1 ) t . print ( 3 )"
+        .to_string()),
+    );
+    assert_eq!(
+      expand(source, "#using std class!(B) #ifdef wrap! ok() #endif"),
+      Ok("local B = ( { } ) ok ( )".to_string()),
+    );
+    let error = expand(source, "#using net log!(1)").unwrap_err();
+    assert!(error.contains("Ambiguous macro `log!`"));
+    assert!(expand(source, "wrap!(1)").is_err());
+    assert!(expand(source, "io::log!(1)").is_err());
+    assert_eq!(
+      expand(source, "do goto skip ::skip:: log!(1) end ::done:: log!(2)"),
+      Ok("do goto skip :: skip :: print ( 1 ) end :: done :: print ( 2 )"
+        .to_string()),
+    );
+    assert!(expand(source, "#using io").is_err());
+  }
+
+  #[test]
+  fn test_private_macros() {
+    let expand_after = |library: &str, code: &str| {
+      let mut tokens = lex("lib.luap", library).unwrap();
+      tokens.extend(lex("<stdin>", code).unwrap());
+      process_tokens(tokens, &mut Macros::default(), &lua::Options::default())
+        .map(render_tokens)
+    };
+    let library = "
+      #private #define helper!(x) = (x + 1) #end
+      #define inc!(x) = helper!(x) #end
+    ";
+    assert_eq!(expand_after(library, "inc!(1)"), Ok("( 1 + 1 )".to_string()));
+    let error = expand_after(library, "helper!(1)").unwrap_err();
+    assert!(error.starts_with("<stdin>:1:1: `helper!` is private to lib.luap"));
+    assert!(expand_after(library, "inc!(helper!(1))").is_err());
+    let library = "
+      #export api!
+      #define api!(x) = impl!(x) #end
+      #define impl!(x) = call(x) #end
+    ";
+    assert_eq!(expand_after(library, "api!(1)"), Ok("call ( 1 )".to_string()));
+    assert!(expand_after(library, "impl!(1)").is_err());
+    assert!(expand_after("#private x = 1", "").is_err());
+  }
+
+  #[test]
+  fn test_lua_blocks() {
+    let mut macros = Macros::default();
+    let mut options = lua::Options {
+      flags: vec!["DEBUG".to_string()],
+      interpreter: None,
+    };
+    let definitions = lex("<stdin>", "
+      #define NAME! = 'app' #end
+      #define twice!(x) = x x #end
+    ").unwrap();
+    process_tokens(definitions, &mut macros, &options).unwrap();
+    let tokens = lex("<block>", "#lua
+      for i = 1, 2 do
+
+        emit('local v' .. i)
+      end
+    #endlua").unwrap();
+    let (directive, body) = (&tokens[0], &tokens[1..tokens.len() - 1]);
+    let script = lua::script(body, &macros, &options);
+    assert!(script.contains("[\"NAME\"] = \"'app'\""));
+    assert!(script.contains("[\"twice\"] = true"));
+    assert!(script.contains("flags = {[\"DEBUG\"] = true}"));
+    let lines = script.lines().collect::<Vec<_>>();
+    assert_eq!(lines[1], "for i = 1 , 2 do");
+    assert_eq!(lines[3], "emit ( 'local v' .. i )");
+    let error = lua::block_error(
+      "lua5.4: stdin:4: attempt to call a nil value\nstack traceback:",
+      body,
+      directive,
+    );
+    assert_eq!(error, "<block>:4:1: Lua error: attempt to call a nil value");
+    options.interpreter = Some("luaproc-missing-interpreter".to_string());
+    let error =
+      lua::run_block(body, directive, &macros, &options).unwrap_err();
+    assert!(error.starts_with(
+      "<block>:1:1: Could not run `luaproc-missing-interpreter` for `#lua`"
+    ));
+  }
+
+  #[test]
+  fn test_lex_keywords() {
+    let tokens = lex("<stdin>", "
+      local trueCount, nilable, is, ending = true, nil
+    ").unwrap();
+    let kinds = tokens
+      .iter()
+      .map(|token| token.kind.clone())
+      .collect::<Vec<_>>();
+    assert_eq!(kinds, vec![
+      TokenKind::Keyword,
+      TokenKind::Name,
+      TokenKind::Delimiter,
+      TokenKind::Name,
+      TokenKind::Delimiter,
+      TokenKind::Name,
+      TokenKind::Delimiter,
+      TokenKind::Name,
+      TokenKind::Operator,
+      TokenKind::Boolean,
+      TokenKind::Delimiter,
+      TokenKind::Nil,
+    ]);
+  }
+
+  #[test]
+  fn test_lex_operators() {
+    let tokens = lex("<stdin>",
+      "x=-1 a..-b c//d<<2~=0x1F ...::l::#t .5e3 0xFE-1 0x1e+1 0x1p-2",
+    ).unwrap();
+    let values = tokens
+      .iter()
+      .map(|token| &*token.value)
+      .collect::<Vec<_>>();
+    assert_eq!(values, vec![
+      "x", "=", "-", "1", "a", "..", "-", "b", "c", "//", "d",
+      "<<", "2", "~=", "0x1F", "...", "::", "l", "::", "#", "t",
+      ".5e3", "0xFE", "-", "1", "0x1e", "+", "1", "0x1p-2",
+    ]);
+    assert!(tokens
+      .iter()
+      .filter(|token| token.kind != TokenKind::Name)
+      .filter(|token| token.kind != TokenKind::Number)
+      .all(|token| token.kind == TokenKind::Operator));
+    let tokens = lex("<stdin>",
+      "print(#scope, #rules) x = #lua return { #using } #scope",
+    ).unwrap();
+    let kinds = tokens
+      .iter()
+      .map(|token| token.kind.clone())
+      .collect::<Vec<_>>();
+    assert_eq!(&kinds[2..4], [TokenKind::Operator, TokenKind::Name]);
+    assert_eq!(&kinds[5..7], [TokenKind::Operator, TokenKind::Name]);
+    assert_eq!(&kinds[10..12], [TokenKind::Operator, TokenKind::Name]);
+    assert_eq!(&kinds[14..16], [TokenKind::Operator, TokenKind::Name]);
+    assert_eq!(kinds[17], TokenKind::Scope);
+    let tokens = lex("<stdin>",
+      "local endfor = {} print(#endfor) #for x in a, b x, #endfor",
+    ).unwrap();
+    let kinds = tokens
+      .iter()
+      .map(|token| token.kind.clone())
+      .collect::<Vec<_>>();
+    assert_eq!(&kinds[7..9], [TokenKind::Operator, TokenKind::Name]);
+    assert_eq!(kinds[18], TokenKind::EndFor);
+    let tokens = lex("<stdin>",
+      "#define a!(x = 1) = #gensym t #end #rules b! () => { #gensym t } #end",
+    ).unwrap();
+    let gensyms = tokens
+      .iter()
+      .filter(|token| token.kind == TokenKind::Gensym)
+      .count();
+    assert_eq!(gensyms, 2);
+  }
+
+  #[test]
+  fn test_lex_locations() {
+    let tokens = lex("<stdin>",
+      "\u{feff}local s = \"h\u{e9}\u{1f600}\" -- \u{e9}\r\nprint(s)",
+    ).unwrap();
+    let locations = tokens
+      .iter()
+      .map(|token| {
+        let location = &token.location;
+        (location.line, location.column, location.utf16_column)
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(locations, vec![
+      (1, 1, 1),
+      (1, 7, 7),
+      (1, 9, 9),
+      (1, 11, 11),
+      (2, 1, 1),
+      (2, 6, 6),
+      (2, 7, 7),
+      (2, 8, 8),
+    ]);
+    let tokens = lex("<stdin>",
+      "\"\u{1f600}\" x",
+    ).unwrap();
+    assert_eq!(tokens[1].location.column, 8);
+    assert_eq!(tokens[1].location.utf16_column, 6);
+  }
+
+  #[test]
+  fn test_lex_errors() {
+    let errors = lex("<stdin>",
+      "local a = 1..2\nprint(`a`, 0x)\nlocal s = \"open\nx = 3e+1\n\
+       local café = 1\nx = 0xFE-1 + 0x1e+1",
+    ).unwrap_err();
+    let errors = errors
+      .into_iter()
+      .map(|error| {
+        let location = error.location;
+        (error.kind, location.line, location.column)
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(errors, vec![
+      (LexErrorKind::MalformedNumber("1..2".to_string()), 1, 11),
+      (LexErrorKind::UnknownCharacter('`'), 2, 7),
+      (LexErrorKind::UnknownCharacter('`'), 2, 9),
+      (LexErrorKind::MalformedNumber("0x".to_string()), 2, 12),
+      (LexErrorKind::UnterminatedString, 3, 11),
+      (LexErrorKind::UnknownCharacter('é'), 5, 10),
+    ]);
+  }
+
+  #[test]
+  fn test_file_locations() {
+    let tokens = lex("lib.luap", "local a\nlocal b = c").unwrap();
+    let location = &tokens[3].location;
+    assert_eq!(location.file, files::intern("lib.luap"));
+    assert_eq!(&*location.file.name(), "lib.luap");
+    assert_eq!(location.file.line(2).as_deref(), Some("local b = c"));
+    assert_eq!(location.to_string(), "lib.luap:2:7");
+    assert_eq!(location.excerpt(), "\n2 | local b = c\n  |       ^");
+    let tokens = lex("cr.luap", "local a\rlocal b = c\n\rx\r\n").unwrap();
+    let location = &tokens[3].location;
+    assert_eq!(location.to_string(), "cr.luap:2:7");
+    assert_eq!(location.excerpt(), "\n2 | local b = c\n  |       ^");
+    assert_eq!(tokens[6].location.file.line(3).as_deref(), Some("x"));
+  }
+
+  #[test]
+  fn test_lex_strings() {
+    let tokens = lex("<stdin>",
+      "a = 'it\\'s' --[==[ ]] \n ]==] b = [[x\n]] ..[=[]]]=] c",
+    ).unwrap();
+    let values = tokens
+      .iter()
+      .map(|token| &*token.value)
+      .collect::<Vec<_>>();
+    assert_eq!(values, vec![
+      "a", "=", "'it\\'s'", "b", "=", "[[x\n]]", "..", "[=[]]]=]", "c",
+    ]);
+    assert_eq!(tokens[3].location.line, 2);
+    assert_eq!(tokens[8].location.line, 3);
+    let errors = lex("<stdin>", "x = [==[ open ]]").unwrap_err();
+    assert_eq!(errors[0].kind, LexErrorKind::UnterminatedString);
+  }
+
+  /// Lexes inputs of growing size, to be run with
+  /// `cargo test --release -- --ignored --nocapture`.
+  #[test]
+  #[ignore]
+  fn bench_lex_scaling() {
+    let line = "local t = { name = \"item\", 0x1F, 2.5e3 } -- data\n";
+    let time = |lines: usize| {
+      let input = line.repeat(lines);
+      let start = std::time::Instant::now();
+      lex("<bench>", &input).unwrap();
+      start.elapsed()
+    };
+    let small = time(10_000);
+    let large = time(80_000);
+    println!("10000 lines: {:?}, 80000 lines: {:?}", small, large);
+    assert!(large < small * 16);
+  }
+
+  /// Expands a macro heavy input of growing size, to be run
+  /// like `bench_lex_scaling`.
+  #[test]
+  #[ignore]
+  fn bench_expand_scaling() {
+    let header = "
+      #define N! = 10 #end
+      #define add!(a, b) = (a + b * N!) #end
+      #define wrap!(x, #...) = { add!(x, 1), #... } #end
+    ";
+    let line = "local t = wrap!(add!(i, 2), \"a\", { b }, [1])\n";
+    let time = |lines: usize| {
+      let input = header.to_string() + &line.repeat(lines);
+      let tokens = lex("<bench>", &input).unwrap();
+      let start = std::time::Instant::now();
+      process_tokens(tokens, &mut Macros::default(), &lua::Options::default())
+        .unwrap();
+      start.elapsed()
+    };
+    let small = time(2_000);
+    let large = time(16_000);
+    println!("2000 lines: {:?}, 16000 lines: {:?}", small, large);
+    assert!(large < small * 16);
+  }
+}
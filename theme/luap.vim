@@ -10,7 +10,7 @@ syn match luaIdentifier "\<[a-z_][a-zA-Z_0-9]*\>"
 syn match luaFunction "\<[a-z_][a-zA-Z_0-9]*\s*\((\|{\|\"\|\[\[\)\@="
 
 syn keyword luaStatement goto
-syn match luaStatement "#\(define\|undef\|end\|ifdef\|ifndef\|endif\|include\|else\)\>"
+syn match luaStatement "#\(define\|undef\|end\|if\|ifdef\|ifndef\|endif\|include\|else\|gensym\|rules\|for\|endfor\|lua\|endlua\|push_macro\|pop_macro\|scope\|endscope\|namespace\|endnamespace\|using\|private\|export\)\>"
 
 syn match luaKeyword "::"
 syn match luaSpecial "#\([a-zA-Z_][a-zA-Z_0-9]*#\)\@="